@@ -0,0 +1,208 @@
+use crate::fan_profile::{read_selected_profile, save_selected_profile, AUTO_PROFILE};
+use shared_utils::control_socket::{
+    error_response, ok_response, parse_duration, serve_control_socket, ControlResponse,
+};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+#[derive(Clone, Copy)]
+struct ManualSpeed {
+    speed: u8,
+    until: Option<Instant>,
+}
+
+#[derive(Default)]
+pub struct FanStatus {
+    pub fan_speed: Option<u8>,
+    pub temperatures: Vec<(String, f32)>,
+    pub profile: Option<String>,
+}
+
+#[derive(Default)]
+struct ControlState {
+    manual_speed: Option<ManualSpeed>,
+    status: FanStatus,
+    profile_names: Vec<String>,
+}
+
+// Serves the control socket from its own thread. Commands that change the fan speed wake up the fan loop.
+pub struct ControlServer {
+    state: Arc<Mutex<ControlState>>,
+    wake_receiver: Receiver<()>,
+    // Keeps the channel open when the socket could not be started.
+    _wake_sender: Sender<()>,
+}
+impl ControlServer {
+    pub fn start(socket_path: &str) -> ControlServer {
+        let state = Arc::new(Mutex::new(ControlState::default()));
+        let (wake_sender, wake_receiver) = channel();
+
+        let handler_state = Arc::clone(&state);
+        let handler_wake_sender = wake_sender.clone();
+        let serve_result = serve_control_socket(socket_path, move |command| {
+            let response = handle_command(command, &handler_state);
+            // Only commands that change the fan need a new reading right away.
+            if command != ["status"] && response[0].1 == "ok" {
+                let _ = handler_wake_sender.send(());
+            }
+            response
+        });
+        if let Err(e) = serve_result {
+            eprintln!(
+                "Error starting the control socket on {}: {}. Continuing without it",
+                socket_path, e
+            );
+        }
+
+        ControlServer {
+            state,
+            wake_receiver,
+            _wake_sender: wake_sender,
+        }
+    }
+
+    // The manual fan speed, if any. Timed speeds are cleared once they expire.
+    pub fn manual_speed(&self) -> Option<u8> {
+        let mut state = self.state.lock().unwrap();
+        let manual_speed = state.manual_speed?;
+        if manual_speed
+            .until
+            .is_some_and(|until| Instant::now() >= until)
+        {
+            println!("Manual fan speed expired, back to automatic control");
+            state.manual_speed = None;
+            return None;
+        }
+        Some(manual_speed.speed)
+    }
+
+    // Profiles come from the config, so they are updated on every reload.
+    pub fn set_profile_names(&self, profile_names: Vec<String>) {
+        self.state.lock().unwrap().profile_names = profile_names;
+    }
+
+    pub fn publish(&self, status: FanStatus) {
+        self.state.lock().unwrap().status = status;
+    }
+
+    // Sleeps for the interval, or less when a command arrives. Returns true when woken by a command.
+    pub fn wait(&self, timeout: Duration) -> bool {
+        self.wake_receiver.recv_timeout(timeout).is_ok()
+    }
+}
+
+fn handle_command(command: &[&str], state: &Mutex<ControlState>) -> ControlResponse {
+    match command {
+        ["status"] => status_response(&state.lock().unwrap()),
+        ["set", speed] => set_manual_speed(state, speed, None),
+        ["set", speed, "for", duration] => match parse_duration(duration) {
+            Some(duration) => set_manual_speed(state, speed, Some(duration)),
+            None => error_response(&format!("Invalid duration: {}", duration)),
+        },
+        ["auto"] => {
+            state.lock().unwrap().manual_speed = None;
+            println!("Manual fan speed cleared, back to automatic control");
+            ok_response()
+        }
+        ["profile", profile_name] => {
+            select_profile(profile_name, &state.lock().unwrap().profile_names)
+        }
+        ["next_profile"] => select_next_profile(&state.lock().unwrap().profile_names),
+        _ => error_response(
+            "Unknown command. Use: status, set <percent> [for <duration>], auto, profile <name> or next_profile",
+        ),
+    }
+}
+
+fn status_response(state: &ControlState) -> ControlResponse {
+    let mut response = ok_response();
+    let mut add_field = |key: &str, value: String| response.push((String::from(key), value));
+
+    match state.manual_speed {
+        Some(manual_speed) => {
+            add_field("mode", String::from("manual"));
+            if let Some(until) = manual_speed.until {
+                let remaining = until.saturating_duration_since(Instant::now());
+                add_field("manual_remaining", remaining.as_secs().to_string());
+            }
+        }
+        None => add_field("mode", String::from("auto")),
+    }
+    if let Some(fan_speed) = state.status.fan_speed {
+        add_field("fan_speed", fan_speed.to_string());
+    }
+    add_field(
+        "profile",
+        state
+            .status
+            .profile
+            .clone()
+            .unwrap_or(String::from("default")),
+    );
+    for (sensor_name, temperature) in &state.status.temperatures {
+        add_field(
+            &format!("temperature.{}", sensor_name),
+            format!("{:.1}", temperature),
+        );
+    }
+    response
+}
+
+fn set_manual_speed(
+    state: &Mutex<ControlState>,
+    speed: &str,
+    duration: Option<Duration>,
+) -> ControlResponse {
+    let speed = match speed.trim_end_matches('%').parse::<u8>() {
+        Ok(speed) if speed <= 100 => speed,
+        _ => return error_response(&format!("Fan speed must be within 0-100. Found: {}", speed)),
+    };
+
+    match duration {
+        Some(duration) => println!("Manual fan speed {} for {:?}", speed, duration),
+        None => println!("Manual fan speed {}", speed),
+    }
+    state.lock().unwrap().manual_speed = Some(ManualSpeed {
+        speed,
+        until: duration.map(|duration| Instant::now() + duration),
+    });
+    ok_response()
+}
+
+// The selection is saved like `argon_fan profile`, the fan loop picks it up when woken.
+fn select_profile(profile_name: &str, profile_names: &[String]) -> ControlResponse {
+    if profile_name != AUTO_PROFILE && !profile_names.iter().any(|name| name == profile_name) {
+        return error_response(&format!("Unknown fan profile: {}", profile_name));
+    }
+
+    match save_selected_profile(profile_name) {
+        Ok(_) => ok_response(),
+        Err(e) => error_response(&format!("Error saving the selected fan profile: {}", e)),
+    }
+}
+
+// Cycles through the profiles in config order, then back to the schedule.
+fn select_next_profile(profile_names: &[String]) -> ControlResponse {
+    if profile_names.is_empty() {
+        return error_response("There are no fan profiles in the config");
+    }
+
+    let selected_index = read_selected_profile().and_then(|selected_profile| {
+        profile_names
+            .iter()
+            .position(|name| *name == selected_profile)
+    });
+    let next_profile = match selected_index {
+        Some(index) if index + 1 < profile_names.len() => profile_names[index + 1].as_str(),
+        Some(_) => AUTO_PROFILE,
+        None => profile_names[0].as_str(),
+    };
+
+    println!("Selecting the next fan profile: {}", next_profile);
+    let mut response = select_profile(next_profile, profile_names);
+    if response[0].1 == "ok" {
+        response.push((String::from("profile"), String::from(next_profile)));
+    }
+    response
+}
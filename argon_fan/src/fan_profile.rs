@@ -0,0 +1,196 @@
+use shared_utils::load_yaml::{ArchivedFanCurve, FanCurve, ScheduleEntry};
+use std::fs::{read_to_string, remove_file, write};
+use std::io;
+
+// The profile selected at runtime is kept here, so it survives reboots.
+pub const PROFILE_STATE_PATH: &str = "/etc/argonone/fan_profile";
+// Clears the selection and goes back to the schedule.
+pub const AUTO_PROFILE: &str = "auto";
+
+pub enum ProfileCurve<'a> {
+    NonArchived(FanCurve),
+    Archived(&'a ArchivedFanCurve),
+}
+
+pub struct FanProfile<'a> {
+    pub name: String,
+    pub curve: ProfileCurve<'a>,
+    pub max_speed: Option<u8>,
+    pub critical_temperature: Option<u8>,
+}
+impl<'a> FanProfile<'a> {
+    pub fn speed_at(&self, temperature: f32) -> u8 {
+        match &self.curve {
+            ProfileCurve::NonArchived(curve) => curve.speed_at(temperature),
+            ProfileCurve::Archived(curve) => curve.speed_at(temperature),
+        }
+    }
+
+    // The speed cap is ignored above the critical temperature.
+    pub fn cap_speed(&self, fan_speed: u8, temperature: f32) -> u8 {
+        match (self.max_speed, self.critical_temperature) {
+            (Some(max_speed), Some(critical_temperature))
+                if temperature < critical_temperature as f32 =>
+            {
+                fan_speed.min(max_speed)
+            }
+            _ => fan_speed,
+        }
+    }
+}
+
+struct LocalTime {
+    weekday: u8,
+    minutes: u16,
+}
+impl LocalTime {
+    fn now() -> io::Result<LocalTime> {
+        // SAFETY: localtime_r only writes into the tm struct we own.
+        unsafe {
+            let timestamp = libc::time(std::ptr::null_mut());
+            let mut local_time: libc::tm = std::mem::zeroed();
+            if libc::localtime_r(&timestamp, &mut local_time).is_null() {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(LocalTime {
+                weekday: local_time.tm_wday as u8,
+                minutes: (local_time.tm_hour * 60 + local_time.tm_min) as u16,
+            })
+        }
+    }
+}
+
+pub fn read_selected_profile() -> Option<String> {
+    match read_to_string(PROFILE_STATE_PATH) {
+        Ok(profile_name) => {
+            let profile_name = profile_name.trim();
+            (!profile_name.is_empty() && profile_name != AUTO_PROFILE)
+                .then(|| String::from(profile_name))
+        }
+        Err(e) if e.kind() == io::ErrorKind::NotFound => None,
+        Err(e) => {
+            eprintln!("Error reading the selected fan profile: {}", e);
+            None
+        }
+    }
+}
+
+pub fn save_selected_profile(profile_name: &str) -> io::Result<()> {
+    if profile_name == AUTO_PROFILE {
+        return match remove_file(PROFILE_STATE_PATH) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        };
+    }
+    write(PROFILE_STATE_PATH, profile_name)
+}
+
+pub struct FanProfiles<'a> {
+    profiles: Vec<FanProfile<'a>>,
+    schedule: Vec<ScheduleEntry>,
+    selected_name: Option<String>,
+    selected: Option<usize>,
+    active: Option<usize>,
+}
+impl<'a> FanProfiles<'a> {
+    pub fn new(profiles: Vec<FanProfile<'a>>, schedule: Vec<ScheduleEntry>) -> FanProfiles<'a> {
+        FanProfiles {
+            profiles,
+            schedule,
+            selected_name: None,
+            selected: None,
+            active: None,
+        }
+    }
+
+    pub fn names(&self) -> Vec<String> {
+        self.profiles
+            .iter()
+            .map(|profile| profile.name.clone())
+            .collect()
+    }
+
+    pub fn active(&self) -> Option<&FanProfile<'a>> {
+        self.active.map(|index| &self.profiles[index])
+    }
+
+    // The selected profile wins over the schedule, without both the fan_config matrix is used.
+    // Returns true when the active profile changed.
+    pub fn update(&mut self) -> bool {
+        self.read_selection();
+        let active = match self.selected {
+            Some(selected) => Some(selected),
+            None => self.scheduled_profile(),
+        };
+
+        self.select(active)
+    }
+
+    fn read_selection(&mut self) {
+        let selected_name = read_selected_profile();
+        if selected_name == self.selected_name {
+            return;
+        }
+
+        self.selected = match &selected_name {
+            Some(profile_name) => {
+                let selected = self
+                    .profiles
+                    .iter()
+                    .position(|profile| &profile.name == profile_name);
+                match selected {
+                    Some(_) => println!("Selected fan profile: {}", profile_name),
+                    None => eprintln!(
+                        "Unknown fan profile selected: {}. Following the schedule",
+                        profile_name
+                    ),
+                }
+                selected
+            }
+            None => {
+                println!("Fan profile selection cleared. Following the schedule");
+                None
+            }
+        };
+        self.selected_name = selected_name;
+    }
+
+    // The profile of the first matching schedule entry.
+    fn scheduled_profile(&self) -> Option<usize> {
+        if self.schedule.is_empty() {
+            return None;
+        }
+
+        let local_time = match LocalTime::now() {
+            Ok(local_time) => local_time,
+            Err(e) => {
+                eprintln!(
+                    "Error reading the local time, keeping the fan profile: {}",
+                    e
+                );
+                return self.active;
+            }
+        };
+        self.schedule
+            .iter()
+            .find(|entry| entry.is_active(local_time.weekday, local_time.minutes))
+            .and_then(|entry| {
+                self.profiles
+                    .iter()
+                    .position(|profile| profile.name == entry.profile)
+            })
+    }
+
+    fn select(&mut self, active: Option<usize>) -> bool {
+        if active == self.active {
+            return false;
+        }
+
+        self.active = active;
+        match self.active() {
+            Some(profile) => println!("Switch to fan profile: {}", profile.name),
+            None => println!("Switch to the default fan profile"),
+        }
+        true
+    }
+}
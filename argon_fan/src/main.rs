@@ -1,115 +1,1035 @@
-use shared_utils::initialize_device::initialize_i2c;
-use shared_utils::load_yaml::{load_argon_config, ArgonConfigValue, TempMatrixRKYV};
-use shared_utils::rkyv::collections::ArchivedHashMap;
+mod control_server;
+mod fan_profile;
+mod pid_controller;
+mod speed_limiter;
+mod temperature_filter;
+
+use crate::control_server::{ControlServer, FanStatus};
+use crate::fan_profile::{
+    read_selected_profile, save_selected_profile, FanProfile, FanProfiles, ProfileCurve,
+    AUTO_PROFILE,
+};
+use crate::pid_controller::PidController;
+use crate::speed_limiter::SpeedLimiter;
+use crate::temperature_filter::TemperatureFilter;
+use shared_utils::config_reload::{run_with_config_reload, ConfigWatcher, ServiceExit};
+use shared_utils::control_socket::FAN_SOCKET_PATH;
+use shared_utils::fan_device::{FanDevice, I2cFanDevice};
+use shared_utils::load_yaml::{
+    load_argon_config, ArchivedFanControl, ArchivedFanCurve, ArgonConfigRKYV, ArgonConfigValue,
+    CriticalAction, CriticalTemperature, FanControl, FanCurve, FilterConfig, Hysteresis,
+    RetryPolicy, ScheduleEntry, SlewRate, SpinUp, TemperatureSourceConfig, MAIN_SENSOR_NAME,
+};
+use shared_utils::power_command::{run_power_command, PowerScriptConfigValue};
+use shared_utils::rkyv::{Deserialize, Infallible};
+use shared_utils::temperature_source::{open_temperature_source, TemperatureSource};
+use std::cell::Cell;
+use std::env;
+use std::process::ExitCode;
 use std::thread;
-use std::time::Duration;
-use systemstat::{Platform, System};
+use std::time::{Duration, Instant};
+
+// How often the config is checked for a reload while waiting to retry the fan device.
+const RELOAD_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+fn main() -> ExitCode {
+    let args: Vec<String> = env::args().collect();
+    if args.get(1).map(String::as_str) == Some("profile") {
+        return select_fan_profile(args.get(2).map(String::as_str));
+    }
+
+    // The control socket outlives config reloads, so a manual fan speed is kept.
+    let control_server = ControlServer::start(FAN_SOCKET_PATH);
+    // Also kept across reloads, so a spinning fan does not get a spin-up kick.
+    let known_fan_speed = Cell::new(None);
+    run_with_config_reload(|final_argon_config| {
+        get_fan_attributes(final_argon_config, &control_server, &known_fan_speed)
+    })
+    .unwrap_or(ExitCode::FAILURE)
+}
+
+// `argon_fan profile <name>` selects a profile for the running service, `auto` goes back to the schedule.
+fn select_fan_profile(profile_name: Option<&str>) -> ExitCode {
+    let profile_name = match profile_name {
+        Some(profile_name) => profile_name,
+        None => {
+            println!(
+                "Selected fan profile: {}",
+                read_selected_profile().unwrap_or(String::from(AUTO_PROFILE))
+            );
+            return ExitCode::SUCCESS;
+        }
+    };
+
+    let mut profile_names: Vec<String> = Vec::new();
+    load_argon_config(|final_argon_config| {
+        profile_names = match final_argon_config {
+            ArgonConfigValue::Archived(archived_argon_config) => archived_argon_config
+                .fan_config
+                .profiles
+                .iter()
+                .map(|profile| profile.name.to_string())
+                .collect(),
+            ArgonConfigValue::NonArchived(non_archived_argon_config) => non_archived_argon_config
+                .fan_config
+                .profiles
+                .into_iter()
+                .map(|profile| profile.name)
+                .collect(),
+        }
+    });
+    if profile_name != AUTO_PROFILE && !profile_names.iter().any(|name| name == profile_name) {
+        eprintln!(
+            "Unknown fan profile: {}. Available profiles: {}, {}",
+            profile_name,
+            profile_names.join(", "),
+            AUTO_PROFILE
+        );
+        return ExitCode::FAILURE;
+    }
+
+    match save_selected_profile(profile_name) {
+        Ok(_) => {
+            println!("Selected fan profile: {}", profile_name);
+            ExitCode::SUCCESS
+        }
+        Err(e) => {
+            eprintln!("Error saving the selected fan profile: {}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+// Every sensor is read, even after an error, so a broken sensor doesn't hide the others from the critical check.
+fn read_sensor_temperatures(sensors: &mut [Sensor]) -> Vec<Result<f32, String>> {
+    sensors
+        .iter_mut()
+        .map(|sensor| {
+            let (raw_temperature, filtered_temperature) = sensor
+                .filter
+                .read(sensor.temperature_source.as_mut())
+                .map_err(|e| format!("[{}] Cannot read temperature: {}", sensor.name, e))?;
+            if sensor.filter.is_active() {
+                println!(
+                    "[{}] Raw temperature: {}. Filtered temperature: {}",
+                    sensor.name, raw_temperature, filtered_temperature
+                );
+            }
+            Ok(filtered_temperature)
+        })
+        .collect()
+}
+
+fn highest_read_temperature(sensor_readings: &[Result<f32, String>]) -> Option<f32> {
+    sensor_readings
+        .iter()
+        .filter_map(|sensor_reading| sensor_reading.as_ref().ok())
+        .copied()
+        .reduce(f32::max)
+}
+
+struct FanConnection<D, F> {
+    open_device: F,
+    device: Option<D>,
+    last_speed: Option<u8>,
+    // The last speed the device accepted. Unlike `last_speed` it is kept after a write error,
+    // so a spinning fan is not taken for a stopped one. None until the first write.
+    known_speed: Option<u8>,
+    last_write: Instant,
+}
+impl<D, E, F> FanConnection<D, F>
+where
+    D: FanDevice,
+    E: std::error::Error + 'static,
+    F: Fn() -> Result<D, E>,
+{
+    fn new(open_device: F, known_speed: Option<u8>) -> FanConnection<D, F> {
+        FanConnection {
+            open_device,
+            device: None,
+            last_speed: None,
+            known_speed,
+            last_write: Instant::now(),
+        }
+    }
+
+    fn fan_stopped(&self) -> bool {
+        matches!(self.known_speed, None | Some(0))
+    }
+
+    // Returns false when the speed is already set on the device and nothing was written.
+    fn set_speed(&mut self, fan_speed: u8) -> Result<bool, Box<dyn std::error::Error>> {
+        if self.last_speed == Some(fan_speed) {
+            return Ok(false);
+        }
+        self.write_speed(fan_speed)?;
+        Ok(true)
+    }
+
+    // Re-asserts the last speed in case the MCU was power-cycled and lost it.
+    fn refresh(&mut self, refresh_interval: Duration) -> Result<(), Box<dyn std::error::Error>> {
+        if let Some(last_speed) = self.last_speed {
+            if self.last_write.elapsed() >= refresh_interval {
+                println!("Refresh fan speed on device: {}", last_speed);
+                self.write_speed(last_speed)?;
+            }
+        }
+        Ok(())
+    }
+
+    // The device is kept open between writes and only re-created after an I/O error.
+    fn write_speed(&mut self, fan_speed: u8) -> Result<(), Box<dyn std::error::Error>> {
+        let mut reconnecting = false;
+        self.last_speed = None;
+        if let Some(device) = self.device.as_mut() {
+            match device.set_speed(fan_speed) {
+                Ok(_) => {
+                    self.last_speed = Some(fan_speed);
+                    self.known_speed = Some(fan_speed);
+                    self.last_write = Instant::now();
+                    return Ok(());
+                }
+                Err(e) => {
+                    eprintln!("Fan device error: {}. Reconnecting...", e);
+                    self.device = None;
+                    reconnecting = true;
+                }
+            }
+        }
+
+        let mut device = (self.open_device)()?;
+        device.set_speed(fan_speed)?;
+        if reconnecting {
+            println!("Fan device reconnected");
+        }
+        self.device = Some(device);
+        self.last_speed = Some(fan_speed);
+        self.known_speed = Some(fan_speed);
+        self.last_write = Instant::now();
+        Ok(())
+    }
+}
 
-fn main() {
-    load_argon_config(|final_argon_config| get_fan_attributes(final_argon_config));
+// Updates every sensor and returns the highest fan speed.
+fn target_fan_speed(
+    sensors: &mut [Sensor],
+    device_temperatures: &[f32],
+    fan_settings: &FanSettings,
+    active_profile: Option<&FanProfile>,
+    force_update: bool,
+) -> u8 {
+    for (sensor, &device_temperature) in sensors.iter_mut().zip(device_temperatures) {
+        let temperature_delta: f32 = device_temperature - sensor.last_temperature;
+
+        println!(
+            "[{}] Last target temperature: {}. Current temperature: {}. Delta: {}",
+            sensor.name, sensor.last_temperature, device_temperature, temperature_delta
+        );
+
+        let hysteresis_exceeded =
+            force_update || fan_settings.hysteresis.exceeded(temperature_delta);
+        // The active fan profile replaces the matrix of the main sensor.
+        let profile = active_profile.filter(|_| sensor.name == MAIN_SENSOR_NAME);
+        // The PID controller runs every tick, hysteresis only applies to the matrix.
+        let fan_speed = match (&mut sensor.controller, profile) {
+            (SpeedController::Pid(pid_controller), _) => {
+                let fan_speed = pid_controller.update(device_temperature);
+                println!("[{}] PID output: {}", sensor.name, fan_speed);
+                Some(fan_speed)
+            }
+            _ if !hysteresis_exceeded => None,
+            (_, Some(profile)) => Some(profile.speed_at(device_temperature)),
+            (SpeedController::NonArchivedMatrix(matrix), None) => {
+                Some(matrix.speed_at(device_temperature))
+            }
+            (SpeedController::ArchivedMatrix(matrix), None) => {
+                Some(matrix.speed_at(device_temperature))
+            }
+        };
+
+        if let Some(fan_speed) = fan_speed {
+            sensor.last_temperature = device_temperature;
+            sensor.fan_speed = fan_speed;
+        }
+    }
+
+    let mut target_speed = sensors
+        .iter()
+        .map(|sensor| sensor.fan_speed)
+        .max()
+        .unwrap_or(0);
+    if let Some(profile) = active_profile {
+        let highest_temperature = device_temperatures.iter().copied().fold(f32::MIN, f32::max);
+        target_speed = profile.cap_speed(target_speed, highest_temperature);
+    }
+    target_speed
 }
 
-fn control_fan(
-    mapped_temperature_matrix: &SpeedTemperatureMatrix,
-    hysteresis: u8,
+fn control_fan<D, E, F>(
+    fan_connection: &mut FanConnection<D, F>,
+    speed_limiter: &mut SpeedLimiter,
+    fan_settings: &FanSettings,
+    target_speed: u8,
+) -> Result<(), Box<dyn std::error::Error>>
+where
+    D: FanDevice,
+    E: std::error::Error + 'static,
+    F: Fn() -> Result<D, E>,
+{
+    // Slew rate and hold time are applied every tick, so the fan keeps moving towards the target.
+    let mut fan_speed = speed_limiter.next_speed(target_speed);
+    if fan_speed > 0 && fan_speed < fan_settings.min_running_speed {
+        fan_speed = fan_settings.min_running_speed;
+    }
+
+    // A fan starting from standstill may stall at low speeds, so it gets a short kick first.
+    if let Some(spin_up) = &fan_settings.spin_up {
+        if fan_connection.fan_stopped() && spin_up.needs_kick(fan_speed) {
+            println!(
+                "Spin up fan at {} for {}ms",
+                spin_up.speed, spin_up.duration
+            );
+            fan_connection.set_speed(spin_up.speed)?;
+            thread::sleep(Duration::from_millis(spin_up.duration));
+        }
+    }
+
+    if fan_connection.set_speed(fan_speed)? {
+        println!(
+            "Set new fan speed to device: {}. Target speed: {}",
+            fan_speed, target_speed
+        );
+    }
+
+    Ok(())
+}
+enum SpeedController<'a> {
+    NonArchivedMatrix(FanCurve),
+    ArchivedMatrix(&'a ArchivedFanCurve),
+    Pid(PidController),
+}
+struct Sensor<'a> {
+    name: String,
+    temperature_source: Box<dyn TemperatureSource>,
+    filter: TemperatureFilter,
+    controller: SpeedController<'a>,
     last_temperature: f32,
-    only_way_down: bool,
-) -> Result<f32, Box<dyn std::error::Error>> {
-    let i2c = initialize_i2c()?;
-    let sys = System::new();
-    let device_temperature: f32 = sys.cpu_temp()?;
-    let temperature_delta: f32 = device_temperature - last_temperature;
-
-    println!(
-        "Last target temperature: {}. Current temperature: {}. Delta: {}",
-        last_temperature, device_temperature, temperature_delta
-    );
+    fan_speed: u8,
+}
+impl<'a> Sensor<'a> {
+    fn new(
+        name: String,
+        temperature_source_config: &TemperatureSourceConfig,
+        filter_config: &FilterConfig,
+        controller: SpeedController<'a>,
+    ) -> Sensor<'a> {
+        println!(
+            "[{}] Temperature source: {:?}",
+            name, temperature_source_config
+        );
+        Sensor {
+            name,
+            temperature_source: open_temperature_source(temperature_source_config),
+            filter: TemperatureFilter::new(filter_config),
+            controller,
+            last_temperature: 0.0,
+            fan_speed: 0,
+        }
+    }
+}
+
+struct CriticalMonitor<'a> {
+    temperature: f32,
+    duration: Duration,
+    action: CriticalAction,
+    shutdown_script: PowerScriptConfigValue<'a>,
+    exceeded_since: Option<Instant>,
+    triggered: bool,
+}
+impl<'a> CriticalMonitor<'a> {
+    fn new(
+        critical: CriticalTemperature,
+        shutdown_script: PowerScriptConfigValue<'a>,
+    ) -> CriticalMonitor<'a> {
+        CriticalMonitor {
+            temperature: critical.temperature as f32,
+            duration: Duration::from_millis(critical.duration),
+            action: critical.action,
+            shutdown_script,
+            exceeded_since: None,
+            triggered: false,
+        }
+    }
+
+    fn check(&mut self, device_temperature: f32) {
+        if self.triggered {
+            return;
+        }
+
+        if device_temperature <= self.temperature {
+            if self.exceeded_since.take().is_some() {
+                println!("Temperature back below critical threshold");
+            }
+            return;
+        }
+
+        let exceeded_since = *self.exceeded_since.get_or_insert_with(Instant::now);
+        let exceeded_time = exceeded_since.elapsed();
+        if exceeded_time < self.duration {
+            eprintln!(
+                "Critical temperature exceeded: {}. Emergency shutdown in {:?}",
+                device_temperature,
+                self.duration - exceeded_time
+            );
+            return;
+        }
 
-    if only_way_down && temperature_delta > 0.0 || temperature_delta.abs() > hysteresis as f32 {
-        let rounded_device_temp = device_temperature.round() as u8;
-        let temp_value = match mapped_temperature_matrix {
-            SpeedTemperatureMatrix::NonArchivedMatrix(matrix) => matrix.get(&rounded_device_temp),
-            SpeedTemperatureMatrix::ArchivedMatrix(matrix) => matrix.get(&rounded_device_temp),
+        eprintln!(
+            "Critical temperature exceeded for {:?}: {}. Starting emergency shutdown...",
+            exceeded_time, device_temperature
+        );
+        let command_result = match self.action {
+            CriticalAction::ShutdownScript => {
+                run_power_command(&self.shutdown_script, "shutdown", vec!["-h", "now"])
+            }
+            CriticalAction::Shutdown => run_power_command(
+                &PowerScriptConfigValue::NonArchivedPower(None),
+                "shutdown",
+                vec!["-h", "now"],
+            ),
         };
 
-        match temp_value {
-            Some(&fan_speed) => {
-                println!("Set new fan speed to device: {}", fan_speed);
-                i2c.smbus_send_byte(fan_speed)?;
-                return Ok(device_temperature);
+        match command_result {
+            Ok(_) => {
+                println!("Power command executed");
+                self.triggered = true;
             }
-            None => {
-                eprintln!("Temperature not found in matrix: {}", {
-                    rounded_device_temp
-                })
+            Err(e) => {
+                eprintln!("Cannot run power command: {}", e);
             }
         }
     }
+}
 
-    Ok(last_temperature)
+// Manual speeds from the control socket are sent as is.
+fn apply_manual_speed<D, E, F>(
+    fan_connection: &mut FanConnection<D, F>,
+    speed_limiter: &mut SpeedLimiter,
+    manual_speed: u8,
+) -> Result<(), Box<dyn std::error::Error>>
+where
+    D: FanDevice,
+    E: std::error::Error + 'static,
+    F: Fn() -> Result<D, E>,
+{
+    speed_limiter.force(manual_speed);
+    if fan_connection.set_speed(manual_speed)? {
+        println!("Set manual fan speed to device: {}", manual_speed);
+    }
+    Ok(())
 }
 
-enum SpeedTemperatureMatrix<'a> {
-    NonArchivedMatrix(TempMatrixRKYV),
-    ArchivedMatrix(&'a ArchivedHashMap<u8, u8>),
+// The retry delay can be long, so reloads are still checked while waiting, and a control command retries at once.
+fn wait_for_retry(
+    config_watcher: &mut ConfigWatcher,
+    control_server: &ControlServer,
+    retry_delay: Duration,
+) -> Option<Box<ArgonConfigRKYV>> {
+    let retry_at = Instant::now() + retry_delay;
+    loop {
+        if let Some(argon_config) = config_watcher.check() {
+            return Some(argon_config);
+        }
+        let remaining = retry_at.saturating_duration_since(Instant::now());
+        if remaining.is_zero() || control_server.wait(remaining.min(RELOAD_CHECK_INTERVAL)) {
+            return None;
+        }
+    }
+}
+
+fn apply_failsafe_speed<D, E, F>(
+    fan_connection: &mut FanConnection<D, F>,
+    speed_limiter: &mut SpeedLimiter,
+    failsafe_speed: u8,
+) where
+    D: FanDevice,
+    E: std::error::Error + 'static,
+    F: Fn() -> Result<D, E>,
+{
+    println!("Set failsafe fan speed to device: {}", failsafe_speed);
+    speed_limiter.force(failsafe_speed);
+    if let Err(e) = fan_connection.write_speed(failsafe_speed) {
+        eprintln!("Cannot set failsafe fan speed: {}", e);
+    }
 }
-fn set_fan_speed_loop(
+
+struct FanSettings {
     interval: Duration,
-    hysteresis: u8,
-    only_way_down: bool,
-    speed_temperature_matrix: SpeedTemperatureMatrix,
-) {
-    let mut retries: u8 = 0;
-    let mut last_temperature: f32 = 0.0;
-
-    while retries <= 3 {
-        match control_fan(
-            &speed_temperature_matrix,
-            hysteresis,
-            last_temperature,
-            only_way_down,
+    hysteresis: Hysteresis,
+    failsafe_speed: u8,
+    retry: RetryPolicy,
+    refresh_interval: Option<Duration>,
+    slew_rate: SlewRate,
+    min_hold_time: Duration,
+    spin_up: Option<SpinUp>,
+    min_running_speed: u8,
+    watch_config: bool,
+}
+
+fn set_fan_speed_loop<D: FanDevice, E: std::error::Error + 'static>(
+    open_device: impl Fn() -> Result<D, E>,
+    mut sensors: Vec<Sensor>,
+    fan_settings: FanSettings,
+    mut critical_monitor: Option<CriticalMonitor>,
+    mut fan_profiles: FanProfiles,
+    control_server: &ControlServer,
+    known_fan_speed: &Cell<Option<u8>>,
+) -> ServiceExit<ExitCode> {
+    let mut fan_connection = FanConnection::new(open_device, known_fan_speed.get());
+    let mut speed_limiter =
+        SpeedLimiter::new(fan_settings.slew_rate.clone(), fan_settings.min_hold_time);
+    let mut config_watcher = ConfigWatcher::new(fan_settings.watch_config);
+    control_server.set_profile_names(fan_profiles.names());
+    let mut failures: u32 = 0;
+
+    loop {
+        if let Some(argon_config) = config_watcher.check() {
+            known_fan_speed.set(fan_connection.known_speed);
+            return ServiceExit::Reload(argon_config);
+        }
+
+        let profile_changed = fan_profiles.update();
+        let sensor_readings = read_sensor_temperatures(&mut sensors);
+        if let (Some(critical_monitor), Some(highest_temperature)) = (
+            critical_monitor.as_mut(),
+            highest_read_temperature(&sensor_readings),
         ) {
-            Ok(new_temperature) => {
-                last_temperature = new_temperature;
-                retries = 0;
+            critical_monitor.check(highest_temperature);
+        }
+        let control_result = sensor_readings
+            .into_iter()
+            .collect::<Result<Vec<f32>, String>>()
+            .map_err(Box::from)
+            .and_then(|device_temperatures| {
+                let target_speed = target_fan_speed(
+                    &mut sensors,
+                    &device_temperatures,
+                    &fan_settings,
+                    fan_profiles.active(),
+                    failures > 0 || profile_changed,
+                );
+                match control_server.manual_speed() {
+                    Some(manual_speed) => {
+                        apply_manual_speed(&mut fan_connection, &mut speed_limiter, manual_speed)?
+                    }
+                    None => control_fan(
+                        &mut fan_connection,
+                        &mut speed_limiter,
+                        &fan_settings,
+                        target_speed,
+                    )?,
+                }
+                control_server.publish(FanStatus {
+                    fan_speed: fan_connection.last_speed,
+                    temperatures: sensors
+                        .iter()
+                        .map(|sensor| sensor.name.clone())
+                        .zip(device_temperatures)
+                        .collect(),
+                    profile: fan_profiles.active().map(|profile| profile.name.clone()),
+                });
+                match fan_settings.refresh_interval {
+                    Some(refresh_interval) => fan_connection.refresh(refresh_interval),
+                    None => Ok(()),
+                }
+            });
+
+        match control_result {
+            Ok(_) => {
+                failures = 0;
+                control_server.wait(fan_settings.interval);
             }
             Err(e) => {
                 eprintln!("Device error: {}", e);
-                retries += retries + 1;
-                thread::sleep(Duration::from_millis(10000));
+                apply_failsafe_speed(
+                    &mut fan_connection,
+                    &mut speed_limiter,
+                    fan_settings.failsafe_speed,
+                );
+                failures += 1;
+                if fan_settings.retry.should_give_up(failures) {
+                    break;
+                }
+                let retry_delay = fan_settings.retry.delay(failures);
+                println!("Retrying in {:?} (attempt {})", retry_delay, failures);
+                if let Some(argon_config) =
+                    wait_for_retry(&mut config_watcher, control_server, retry_delay)
+                {
+                    known_fan_speed.set(fan_connection.known_speed);
+                    return ServiceExit::Reload(argon_config);
+                }
             }
         };
-        thread::sleep(interval);
     }
+
+    eprintln!("Too many errors, stopping fan control");
+    apply_failsafe_speed(
+        &mut fan_connection,
+        &mut speed_limiter,
+        fan_settings.failsafe_speed,
+    );
+    ServiceExit::Finished(ExitCode::FAILURE)
 }
-fn get_fan_attributes(argon_config_value: ArgonConfigValue) {
+fn get_fan_attributes(
+    argon_config_value: ArgonConfigValue,
+    control_server: &ControlServer,
+    known_fan_speed: &Cell<Option<u8>>,
+) -> ServiceExit<ExitCode> {
     match argon_config_value {
         ArgonConfigValue::Archived(archived_argon_config) => {
-            let interval = Duration::from_millis(archived_argon_config.fan_config.interval);
-            let hysteresis = archived_argon_config.fan_config.hysteresis.amount;
-            let only_way_down = archived_argon_config.fan_config.hysteresis.only_way_down;
-            let speed_temperature_matrix = &archived_argon_config.fan_config.matrix;
+            let fan_settings = FanSettings {
+                interval: Duration::from_millis(archived_argon_config.fan_config.interval),
+                hysteresis: archived_argon_config
+                    .fan_config
+                    .hysteresis
+                    .deserialize(&mut Infallible)
+                    .unwrap(),
+                failsafe_speed: archived_argon_config.fan_config.failsafe_speed,
+                retry: archived_argon_config
+                    .fan_config
+                    .retry
+                    .deserialize(&mut Infallible)
+                    .unwrap(),
+                refresh_interval: archived_argon_config
+                    .fan_config
+                    .refresh_interval
+                    .as_ref()
+                    .map(|&refresh_interval| Duration::from_millis(refresh_interval)),
+                slew_rate: archived_argon_config
+                    .fan_config
+                    .slew_rate
+                    .deserialize(&mut Infallible)
+                    .unwrap(),
+                min_hold_time: Duration::from_millis(
+                    archived_argon_config.fan_config.min_hold_time,
+                ),
+                spin_up: archived_argon_config
+                    .fan_config
+                    .spin_up
+                    .deserialize(&mut Infallible)
+                    .unwrap(),
+                min_running_speed: archived_argon_config.fan_config.min_running_speed,
+                watch_config: archived_argon_config.watch_config,
+            };
+            let critical: Option<CriticalTemperature> = archived_argon_config
+                .fan_config
+                .critical
+                .deserialize(&mut Infallible)
+                .unwrap();
+            let critical_monitor = critical.map(|critical| {
+                CriticalMonitor::new(
+                    critical,
+                    PowerScriptConfigValue::ArchivedPower(&archived_argon_config.shutdown_script),
+                )
+            });
+            let filter_config: FilterConfig = archived_argon_config
+                .fan_config
+                .filter
+                .deserialize(&mut Infallible)
+                .unwrap();
+            let sensors = archived_argon_config
+                .fan_config
+                .sensors
+                .iter()
+                .map(|archived_sensor| {
+                    let temperature_source_config: TemperatureSourceConfig = archived_sensor
+                        .temperature_source
+                        .deserialize(&mut Infallible)
+                        .unwrap();
+                    let controller = match &archived_sensor.control {
+                        ArchivedFanControl::Matrix(matrix) => {
+                            SpeedController::ArchivedMatrix(matrix)
+                        }
+                        ArchivedFanControl::Pid(pid) => SpeedController::Pid(PidController::new(
+                            pid.deserialize(&mut Infallible).unwrap(),
+                        )),
+                    };
+                    Sensor::new(
+                        archived_sensor.name.to_string(),
+                        &temperature_source_config,
+                        &filter_config,
+                        controller,
+                    )
+                })
+                .collect();
+            let profiles = archived_argon_config
+                .fan_config
+                .profiles
+                .iter()
+                .map(|archived_profile| FanProfile {
+                    name: archived_profile.name.to_string(),
+                    curve: ProfileCurve::Archived(&archived_profile.curve),
+                    max_speed: archived_profile.max_speed.as_ref().copied(),
+                    critical_temperature: archived_profile.critical_temperature.as_ref().copied(),
+                })
+                .collect();
+            let schedule: Vec<ScheduleEntry> = archived_argon_config
+                .fan_config
+                .schedule
+                .deserialize(&mut Infallible)
+                .unwrap();
             set_fan_speed_loop(
-                interval,
-                hysteresis,
-                only_way_down,
-                SpeedTemperatureMatrix::ArchivedMatrix(speed_temperature_matrix),
-            );
+                I2cFanDevice::new,
+                sensors,
+                fan_settings,
+                critical_monitor,
+                FanProfiles::new(profiles, schedule),
+                control_server,
+                known_fan_speed,
+            )
         }
         ArgonConfigValue::NonArchived(non_archived_argon_config) => {
-            let interval = Duration::from_millis(non_archived_argon_config.fan_config.interval);
-            let hysteresis = non_archived_argon_config.fan_config.hysteresis.amount;
-            let only_way_down = non_archived_argon_config
+            let fan_settings = FanSettings {
+                interval: Duration::from_millis(non_archived_argon_config.fan_config.interval),
+                hysteresis: non_archived_argon_config.fan_config.hysteresis,
+                failsafe_speed: non_archived_argon_config.fan_config.failsafe_speed,
+                retry: non_archived_argon_config.fan_config.retry,
+                refresh_interval: non_archived_argon_config
+                    .fan_config
+                    .refresh_interval
+                    .map(Duration::from_millis),
+                slew_rate: non_archived_argon_config.fan_config.slew_rate,
+                min_hold_time: Duration::from_millis(
+                    non_archived_argon_config.fan_config.min_hold_time,
+                ),
+                spin_up: non_archived_argon_config.fan_config.spin_up,
+                min_running_speed: non_archived_argon_config.fan_config.min_running_speed,
+                watch_config: non_archived_argon_config.watch_config,
+            };
+            let shutdown_script = non_archived_argon_config.shutdown_script;
+            let critical_monitor = non_archived_argon_config
+                .fan_config
+                .critical
+                .map(|critical| {
+                    CriticalMonitor::new(
+                        critical,
+                        PowerScriptConfigValue::NonArchivedPower(shutdown_script),
+                    )
+                });
+            let filter_config = non_archived_argon_config.fan_config.filter;
+            let sensors = non_archived_argon_config
+                .fan_config
+                .sensors
+                .into_iter()
+                .map(|sensor| {
+                    let controller = match sensor.control {
+                        FanControl::Matrix(matrix) => SpeedController::NonArchivedMatrix(matrix),
+                        FanControl::Pid(pid) => SpeedController::Pid(PidController::new(pid)),
+                    };
+                    Sensor::new(
+                        sensor.name,
+                        &sensor.temperature_source,
+                        &filter_config,
+                        controller,
+                    )
+                })
+                .collect();
+            let profiles = non_archived_argon_config
                 .fan_config
-                .hysteresis
-                .only_way_down;
-            let speed_temperature_matrix = non_archived_argon_config.fan_config.matrix;
+                .profiles
+                .into_iter()
+                .map(|profile| FanProfile {
+                    name: profile.name,
+                    curve: ProfileCurve::NonArchived(profile.curve),
+                    max_speed: profile.max_speed,
+                    critical_temperature: profile.critical_temperature,
+                })
+                .collect();
             set_fan_speed_loop(
-                interval,
-                hysteresis,
-                only_way_down,
-                SpeedTemperatureMatrix::NonArchivedMatrix(speed_temperature_matrix),
-            );
+                I2cFanDevice::new,
+                sensors,
+                fan_settings,
+                critical_monitor,
+                FanProfiles::new(profiles, non_archived_argon_config.fan_config.schedule),
+                control_server,
+                known_fan_speed,
+            )
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use shared_utils::fan_device::MockFanDevice;
+    use shared_utils::load_yaml::Interpolation;
+    use shared_utils::temperature_source::FixedSource;
+    use std::io;
+
+    fn test_fan_settings() -> FanSettings {
+        FanSettings {
+            interval: Duration::from_millis(10000),
+            hysteresis: Hysteresis {
+                rise: 0.0,
+                fall: 0.0,
+            },
+            failsafe_speed: 100,
+            retry: RetryPolicy::default(),
+            refresh_interval: None,
+            slew_rate: SlewRate::default(),
+            min_hold_time: Duration::ZERO,
+            spin_up: None,
+            min_running_speed: 0,
+            watch_config: false,
         }
     }
+
+    fn fixed_sensor(temperatures: Vec<f32>) -> Sensor<'static> {
+        Sensor {
+            name: String::from(MAIN_SENSOR_NAME),
+            temperature_source: Box::new(FixedSource::new(temperatures)),
+            filter: TemperatureFilter::new(&FilterConfig::None),
+            controller: SpeedController::NonArchivedMatrix(FanCurve {
+                interpolation: Interpolation::Step,
+                points: vec![[50, 20], [60, 50], [70, 100]],
+                tangents: Vec::new(),
+            }),
+            last_temperature: 0.0,
+            fan_speed: 0,
+        }
+    }
+
+    // One iteration of the fan loop, without the control socket and the profiles.
+    fn run_tick<F>(
+        sensors: &mut [Sensor],
+        fan_connection: &mut FanConnection<MockFanDevice, F>,
+        speed_limiter: &mut SpeedLimiter,
+        fan_settings: &FanSettings,
+    ) -> Result<(), Box<dyn std::error::Error>>
+    where
+        F: Fn() -> Result<MockFanDevice, io::Error>,
+    {
+        let device_temperatures = read_sensor_temperatures(sensors)
+            .into_iter()
+            .collect::<Result<Vec<f32>, String>>()?;
+        let target_speed =
+            target_fan_speed(sensors, &device_temperatures, fan_settings, None, false);
+        control_fan(fan_connection, speed_limiter, fan_settings, target_speed)
+    }
+
+    fn test_speed_limiter(fan_settings: &FanSettings) -> SpeedLimiter {
+        SpeedLimiter::new(fan_settings.slew_rate.clone(), fan_settings.min_hold_time)
+    }
+
+    #[test]
+    fn control_fan_writes_the_matrix_speed() {
+        let fan_settings = test_fan_settings();
+        let mock_device = MockFanDevice::new();
+        let mut fan_connection = FanConnection::new(|| Ok(mock_device.clone()), None);
+        let mut speed_limiter = test_speed_limiter(&fan_settings);
+        let mut sensors = vec![fixed_sensor(vec![55.0, 65.0, 75.0, 45.0])];
+
+        for _ in 0..4 {
+            run_tick(
+                &mut sensors,
+                &mut fan_connection,
+                &mut speed_limiter,
+                &fan_settings,
+            )
+            .unwrap();
+        }
+
+        assert_eq!(mock_device.written_bytes(), vec![20, 50, 100, 0]);
+    }
+
+    #[test]
+    fn unchanged_fan_speed_is_not_written_again() {
+        let fan_settings = test_fan_settings();
+        let mock_device = MockFanDevice::new();
+        let mut fan_connection = FanConnection::new(|| Ok(mock_device.clone()), None);
+        let mut speed_limiter = test_speed_limiter(&fan_settings);
+        let mut sensors = vec![fixed_sensor(vec![55.0, 56.0, 57.0, 62.0])];
+
+        for _ in 0..4 {
+            run_tick(
+                &mut sensors,
+                &mut fan_connection,
+                &mut speed_limiter,
+                &fan_settings,
+            )
+            .unwrap();
+        }
+
+        assert_eq!(mock_device.written_bytes(), vec![20, 50]);
+    }
+
+    #[test]
+    fn fan_device_is_reopened_after_a_write_error() {
+        let fan_settings = test_fan_settings();
+        let mock_device = MockFanDevice::new();
+        let opened_devices = Cell::new(0);
+        let mut fan_connection = FanConnection::new(
+            || {
+                opened_devices.set(opened_devices.get() + 1);
+                Ok(mock_device.clone())
+            },
+            None,
+        );
+        let mut speed_limiter = test_speed_limiter(&fan_settings);
+        let mut sensors = vec![fixed_sensor(vec![55.0, 65.0, 65.0])];
+
+        run_tick(
+            &mut sensors,
+            &mut fan_connection,
+            &mut speed_limiter,
+            &fan_settings,
+        )
+        .unwrap();
+        assert_eq!(opened_devices.get(), 1);
+
+        // The write fails on the open device and succeeds on the reopened one.
+        mock_device.fail_next_writes(1);
+        run_tick(
+            &mut sensors,
+            &mut fan_connection,
+            &mut speed_limiter,
+            &fan_settings,
+        )
+        .unwrap();
+        assert_eq!(opened_devices.get(), 2);
+        assert_eq!(mock_device.written_bytes(), vec![20, 50]);
+        assert_eq!(fan_connection.last_speed, Some(50));
+
+        // Both writes fail, so the speed is unknown and sent again on the next tick.
+        mock_device.fail_next_writes(2);
+        sensors[0].temperature_source = Box::new(FixedSource::new(vec![75.0, 75.0]));
+        assert!(run_tick(
+            &mut sensors,
+            &mut fan_connection,
+            &mut speed_limiter,
+            &fan_settings,
+        )
+        .is_err());
+        assert_eq!(fan_connection.last_speed, None);
+        run_tick(
+            &mut sensors,
+            &mut fan_connection,
+            &mut speed_limiter,
+            &fan_settings,
+        )
+        .unwrap();
+        assert_eq!(mock_device.written_bytes(), vec![20, 50, 100]);
+        assert_eq!(fan_connection.last_speed, Some(100));
+    }
+
+    #[test]
+    fn failsafe_speed_is_always_written() {
+        let fan_settings = test_fan_settings();
+        let mock_device = MockFanDevice::new();
+        let mut fan_connection = FanConnection::new(|| Ok(mock_device.clone()), None);
+        let mut speed_limiter = test_speed_limiter(&fan_settings);
+        let mut sensors = vec![fixed_sensor(vec![75.0])];
+
+        run_tick(
+            &mut sensors,
+            &mut fan_connection,
+            &mut speed_limiter,
+            &fan_settings,
+        )
+        .unwrap();
+        // The failsafe speed is written even when the device should already have it.
+        apply_failsafe_speed(
+            &mut fan_connection,
+            &mut speed_limiter,
+            fan_settings.failsafe_speed,
+        );
+
+        assert_eq!(mock_device.written_bytes(), vec![100, 100]);
+        assert_eq!(fan_connection.last_speed, Some(100));
+    }
+
+    fn spin_up_fan_settings() -> FanSettings {
+        FanSettings {
+            spin_up: Some(SpinUp {
+                speed: 60,
+                duration: 0,
+                threshold: None,
+            }),
+            ..test_fan_settings()
+        }
+    }
+
+    #[test]
+    fn spin_up_kicks_only_a_stopped_fan() {
+        let fan_settings = spin_up_fan_settings();
+        let mock_device = MockFanDevice::new();
+        let mut fan_connection = FanConnection::new(|| Ok(mock_device.clone()), None);
+        let mut speed_limiter = test_speed_limiter(&fan_settings);
+        let mut sensors = vec![fixed_sensor(vec![55.0, 45.0, 55.0, 65.0])];
+
+        for _ in 0..4 {
+            run_tick(
+                &mut sensors,
+                &mut fan_connection,
+                &mut speed_limiter,
+                &fan_settings,
+            )
+            .unwrap();
+        }
+
+        assert_eq!(mock_device.written_bytes(), vec![60, 20, 0, 60, 20, 50]);
+    }
+
+    #[test]
+    fn spin_up_is_skipped_after_a_reconnect_or_a_reload() {
+        let fan_settings = spin_up_fan_settings();
+        let mock_device = MockFanDevice::new();
+        let mut fan_connection = FanConnection::new(|| Ok(mock_device.clone()), None);
+        let mut speed_limiter = test_speed_limiter(&fan_settings);
+        let mut sensors = vec![fixed_sensor(vec![65.0, 55.0])];
+
+        run_tick(
+            &mut sensors,
+            &mut fan_connection,
+            &mut speed_limiter,
+            &fan_settings,
+        )
+        .unwrap();
+        mock_device.fail_next_writes(1);
+        run_tick(
+            &mut sensors,
+            &mut fan_connection,
+            &mut speed_limiter,
+            &fan_settings,
+        )
+        .unwrap();
+        assert_eq!(mock_device.written_bytes(), vec![60, 50, 20]);
+
+        // A reload creates a new connection with the speed known before it.
+        let mut fan_connection =
+            FanConnection::new(|| Ok(mock_device.clone()), fan_connection.known_speed);
+        let mut speed_limiter = test_speed_limiter(&fan_settings);
+        let mut sensors = vec![fixed_sensor(vec![55.0])];
+        run_tick(
+            &mut sensors,
+            &mut fan_connection,
+            &mut speed_limiter,
+            &fan_settings,
+        )
+        .unwrap();
+        assert_eq!(mock_device.written_bytes(), vec![60, 50, 20, 20]);
+    }
+
+    #[test]
+    fn a_broken_sensor_does_not_hide_the_others_from_the_critical_check() {
+        let mut broken_sensor = fixed_sensor(Vec::new());
+        broken_sensor.name = String::from("nvme");
+        let mut sensors = vec![broken_sensor, fixed_sensor(vec![90.0])];
+
+        let sensor_readings = read_sensor_temperatures(&mut sensors);
+
+        assert!(sensor_readings[0].is_err());
+        assert_eq!(highest_read_temperature(&sensor_readings), Some(90.0));
+        assert_eq!(highest_read_temperature(&sensor_readings[..1]), None);
+    }
 }
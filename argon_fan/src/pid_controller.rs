@@ -0,0 +1,116 @@
+use shared_utils::load_yaml::PidConfig;
+use std::time::Instant;
+
+pub struct PidController {
+    config: PidConfig,
+    integral: f32,
+    last_temperature: Option<f32>,
+    last_update: Option<Instant>,
+}
+impl PidController {
+    pub fn new(config: PidConfig) -> PidController {
+        PidController {
+            config,
+            integral: 0.0,
+            last_temperature: None,
+            last_update: None,
+        }
+    }
+
+    pub fn update(&mut self, temperature: f32) -> u8 {
+        let now = Instant::now();
+        let elapsed_seconds = self
+            .last_update
+            .map(|last_update| now.duration_since(last_update).as_secs_f32());
+        self.last_update = Some(now);
+        self.compute(temperature, elapsed_seconds)
+    }
+
+    // The error is positive when the temperature is above the target, so more error means more fan.
+    fn compute(&mut self, temperature: f32, elapsed_seconds: Option<f32>) -> u8 {
+        let min_speed = self.config.min_speed as f32;
+        let max_speed = self.config.max_speed as f32;
+        let error = temperature - self.config.target;
+
+        // Derivative on measurement, a target change does not kick the fan.
+        let derivative = match (self.last_temperature, elapsed_seconds) {
+            (Some(last_temperature), Some(seconds)) if seconds > 0.0 => {
+                (temperature - last_temperature) / seconds
+            }
+            _ => 0.0,
+        };
+        self.last_temperature = Some(temperature);
+
+        let proportional_output = self.config.kp * error + self.config.kd * derivative;
+        let mut integral = self.integral;
+        if let Some(seconds) = elapsed_seconds {
+            integral += self.config.ki * error * seconds;
+        }
+
+        // Anti-windup: stop integrating while the output is saturated in the same direction.
+        let unclamped_output = proportional_output + integral;
+        let saturated_high = unclamped_output > max_speed && error > 0.0;
+        let saturated_low = unclamped_output < min_speed && error < 0.0;
+        if !saturated_high && !saturated_low {
+            self.integral = integral.clamp(min_speed - max_speed, max_speed);
+        }
+
+        let output = (proportional_output + self.integral).clamp(min_speed, max_speed);
+        output.round() as u8
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pid_controller(kp: f32, ki: f32, kd: f32, min_speed: u8, max_speed: u8) -> PidController {
+        PidController::new(PidConfig {
+            target: 50.0,
+            kp,
+            ki,
+            kd,
+            min_speed,
+            max_speed,
+        })
+    }
+
+    #[test]
+    fn output_is_clamped_to_the_speed_limits() {
+        let mut pid_controller = pid_controller(10.0, 0.0, 0.0, 20, 80);
+
+        assert_eq!(pid_controller.compute(90.0, Some(1.0)), 80);
+        assert_eq!(pid_controller.compute(10.0, Some(1.0)), 20);
+        assert_eq!(pid_controller.compute(55.0, Some(1.0)), 50);
+    }
+
+    #[test]
+    fn integral_accumulates_while_not_saturated() {
+        let mut pid_controller = pid_controller(0.0, 1.0, 0.0, 0, 100);
+
+        assert_eq!(pid_controller.compute(60.0, Some(1.0)), 10);
+        assert_eq!(pid_controller.compute(60.0, Some(1.0)), 20);
+    }
+
+    #[test]
+    fn integral_stops_growing_while_saturated() {
+        let mut pid_controller = pid_controller(2.0, 1.0, 0.0, 0, 100);
+
+        for _ in 0..10 {
+            assert_eq!(pid_controller.compute(120.0, Some(10.0)), 100);
+        }
+        assert_eq!(pid_controller.integral, 0.0);
+
+        // Back at the target, the fan is not held at full speed by a wound up integral.
+        assert_eq!(pid_controller.compute(50.0, Some(10.0)), 0);
+    }
+
+    #[test]
+    fn first_sample_has_no_derivative_kick() {
+        let mut pid_controller = pid_controller(0.0, 0.0, 100.0, 0, 100);
+
+        assert_eq!(pid_controller.compute(90.0, None), 0);
+        assert_eq!(pid_controller.compute(90.0, Some(1.0)), 0);
+        assert_eq!(pid_controller.compute(90.5, Some(1.0)), 50);
+    }
+}
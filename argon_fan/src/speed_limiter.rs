@@ -0,0 +1,134 @@
+use shared_utils::load_yaml::SlewRate;
+use std::time::{Duration, Instant};
+
+pub struct SpeedLimiter {
+    slew_rate: SlewRate,
+    min_hold_time: Duration,
+    current_speed: Option<u8>,
+    changed_at: Instant,
+}
+impl SpeedLimiter {
+    pub fn new(slew_rate: SlewRate, min_hold_time: Duration) -> SpeedLimiter {
+        SpeedLimiter {
+            slew_rate,
+            min_hold_time,
+            current_speed: None,
+            changed_at: Instant::now(),
+        }
+    }
+
+    // Moves one step towards the target speed. The first speed is applied as is.
+    pub fn next_speed(&mut self, target_speed: u8) -> u8 {
+        let current_speed = match self.current_speed {
+            Some(current_speed) => current_speed,
+            None => {
+                self.force(target_speed);
+                return target_speed;
+            }
+        };
+
+        let next_speed = if target_speed > current_speed {
+            match self.slew_rate.up {
+                Some(max_step) => target_speed.min(current_speed.saturating_add(max_step)),
+                None => target_speed,
+            }
+        } else if target_speed < current_speed {
+            if self.changed_at.elapsed() < self.min_hold_time {
+                current_speed
+            } else {
+                match self.slew_rate.down {
+                    Some(max_step) => target_speed.max(current_speed.saturating_sub(max_step)),
+                    None => target_speed,
+                }
+            }
+        } else {
+            current_speed
+        };
+
+        if next_speed != current_speed {
+            self.force(next_speed);
+        }
+        next_speed
+    }
+
+    // Used when the speed is set outside of the limiter, e.g. by the failsafe.
+    pub fn force(&mut self, speed: u8) {
+        self.current_speed = Some(speed);
+        self.changed_at = Instant::now();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HOLD_TIME: Duration = Duration::from_secs(60);
+
+    fn speed_limiter(up: Option<u8>, down: Option<u8>, min_hold_time: Duration) -> SpeedLimiter {
+        SpeedLimiter::new(SlewRate { up, down }, min_hold_time)
+    }
+
+    fn expire_hold_time(speed_limiter: &mut SpeedLimiter) {
+        speed_limiter.changed_at = Instant::now().checked_sub(HOLD_TIME).unwrap();
+    }
+
+    #[test]
+    fn first_speed_is_applied_as_is() {
+        let mut speed_limiter = speed_limiter(Some(10), Some(10), HOLD_TIME);
+
+        assert_eq!(speed_limiter.next_speed(80), 80);
+    }
+
+    #[test]
+    fn steps_are_capped_in_both_directions() {
+        let mut speed_limiter = speed_limiter(Some(10), Some(25), Duration::ZERO);
+        speed_limiter.force(20);
+
+        assert_eq!(speed_limiter.next_speed(100), 30);
+        assert_eq!(speed_limiter.next_speed(100), 40);
+        assert_eq!(speed_limiter.next_speed(45), 45);
+        assert_eq!(speed_limiter.next_speed(0), 20);
+        assert_eq!(speed_limiter.next_speed(0), 0);
+    }
+
+    #[test]
+    fn missing_slew_rate_does_not_limit_that_direction() {
+        let mut speed_limiter = speed_limiter(None, Some(10), Duration::ZERO);
+        speed_limiter.force(20);
+
+        assert_eq!(speed_limiter.next_speed(100), 100);
+        assert_eq!(speed_limiter.next_speed(0), 90);
+    }
+
+    #[test]
+    fn decreases_wait_for_the_hold_time() {
+        let mut speed_limiter = speed_limiter(None, None, HOLD_TIME);
+        speed_limiter.force(60);
+
+        assert_eq!(speed_limiter.next_speed(20), 60);
+
+        expire_hold_time(&mut speed_limiter);
+        assert_eq!(speed_limiter.next_speed(20), 20);
+        assert_eq!(speed_limiter.next_speed(10), 20);
+    }
+
+    #[test]
+    fn increases_ignore_the_hold_time() {
+        let mut speed_limiter = speed_limiter(Some(30), None, HOLD_TIME);
+        speed_limiter.force(20);
+
+        assert_eq!(speed_limiter.next_speed(100), 50);
+        assert_eq!(speed_limiter.next_speed(100), 80);
+    }
+
+    #[test]
+    fn force_restarts_the_hold_time() {
+        let mut speed_limiter = speed_limiter(None, None, HOLD_TIME);
+        speed_limiter.force(60);
+        expire_hold_time(&mut speed_limiter);
+
+        speed_limiter.force(80);
+
+        assert_eq!(speed_limiter.next_speed(20), 80);
+    }
+}
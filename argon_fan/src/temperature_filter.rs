@@ -0,0 +1,100 @@
+use shared_utils::load_yaml::FilterConfig;
+use shared_utils::temperature_source::TemperatureSource;
+use std::collections::VecDeque;
+use std::io;
+use std::thread;
+use std::time::Duration;
+
+pub enum TemperatureFilter {
+    None,
+    Ema {
+        alpha: f32,
+        average: Option<f32>,
+    },
+    Median {
+        window: usize,
+        readings: VecDeque<f32>,
+    },
+    Mean {
+        samples: u8,
+        sample_interval: Duration,
+    },
+}
+impl TemperatureFilter {
+    pub fn new(filter_config: &FilterConfig) -> TemperatureFilter {
+        match *filter_config {
+            FilterConfig::None => TemperatureFilter::None,
+            FilterConfig::Ema { alpha } => TemperatureFilter::Ema {
+                alpha,
+                average: None,
+            },
+            FilterConfig::Median { window } => TemperatureFilter::Median {
+                window: window as usize,
+                readings: VecDeque::with_capacity(window as usize),
+            },
+            FilterConfig::Mean {
+                samples,
+                sample_interval,
+            } => TemperatureFilter::Mean {
+                samples,
+                sample_interval: Duration::from_millis(sample_interval),
+            },
+        }
+    }
+
+    pub fn is_active(&self) -> bool {
+        !matches!(self, TemperatureFilter::None)
+    }
+
+    // Returns the raw reading and the filtered temperature.
+    pub fn read(
+        &mut self,
+        temperature_source: &mut dyn TemperatureSource,
+    ) -> io::Result<(f32, f32)> {
+        match self {
+            TemperatureFilter::None => {
+                let temperature = temperature_source.read_temperature()?;
+                Ok((temperature, temperature))
+            }
+            TemperatureFilter::Ema { alpha, average } => {
+                let temperature = temperature_source.read_temperature()?;
+                let filtered_temperature = match *average {
+                    Some(last_average) => last_average + *alpha * (temperature - last_average),
+                    None => temperature,
+                };
+                *average = Some(filtered_temperature);
+                Ok((temperature, filtered_temperature))
+            }
+            TemperatureFilter::Median { window, readings } => {
+                let temperature = temperature_source.read_temperature()?;
+                if readings.len() == *window {
+                    readings.pop_front();
+                }
+                readings.push_back(temperature);
+
+                let mut sorted_readings: Vec<f32> = readings.iter().copied().collect();
+                sorted_readings.sort_by(f32::total_cmp);
+                let middle = sorted_readings.len() / 2;
+                let filtered_temperature = if sorted_readings.len() % 2 == 1 {
+                    sorted_readings[middle]
+                } else {
+                    (sorted_readings[middle - 1] + sorted_readings[middle]) / 2.0
+                };
+                Ok((temperature, filtered_temperature))
+            }
+            TemperatureFilter::Mean {
+                samples,
+                sample_interval,
+            } => {
+                let mut temperature = temperature_source.read_temperature()?;
+                let mut total = temperature;
+                for _ in 1..*samples {
+                    thread::sleep(*sample_interval);
+                    temperature = temperature_source.read_temperature()?;
+                    total += temperature;
+                }
+                Ok((temperature, total / *samples as f32))
+            }
+        }
+    }
+}
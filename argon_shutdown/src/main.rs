@@ -1,5 +1,5 @@
-use shared_utils::initialize_device::{initialize_i2c, CUT_POWER_BYTE, TURN_OFF_FAN};
-use shared_utils::rppal::i2c::{Error, I2c};
+use shared_utils::fan_device::{FanDevice, I2cFanDevice};
+use shared_utils::rppal::i2c::Error;
 use std::env;
 
 fn main() {
@@ -9,7 +9,7 @@ fn main() {
         .iter()
         .find(|&arg| arg == "halt" || arg == "poweroff" || arg == "reboot" || arg == "kexec")
     {
-        Some(arg) => match send_smbus_bytes(initialize_i2c(), arg) {
+        Some(arg) => match send_smbus_bytes(I2cFanDevice::new(), arg) {
             Ok(_) => {
                 println!("Power off ran successfully")
             }
@@ -23,14 +23,17 @@ fn main() {
     }
 }
 
-fn send_smbus_bytes(i2c_result: Result<I2c, Error>, arg: &str) -> Result<(), Error> {
-    let i2c = i2c_result?;
+fn send_smbus_bytes<D: FanDevice<Error = Error>>(
+    device_result: Result<D, Error>,
+    arg: &str,
+) -> Result<(), Error> {
+    let mut device = device_result?;
     // Turn off fan signal
-    i2c.smbus_send_byte(TURN_OFF_FAN)?;
+    device.turn_off()?;
 
     // Power cut signal
     match arg {
-        "halt" | "poweroff" => i2c.smbus_send_byte(CUT_POWER_BYTE),
+        "halt" | "poweroff" => device.power_cut(),
         _ => Ok(()),
     }
 }
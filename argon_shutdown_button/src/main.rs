@@ -1,17 +1,44 @@
+use shared_utils::config_reload::{run_with_config_reload, ConfigWatcher, ServiceExit};
+use shared_utils::control_socket::{
+    error_response, ok_response, send_command, serve_control_socket, BUTTON_SOCKET_PATH,
+    FAN_SOCKET_PATH,
+};
 use shared_utils::initialize_device::initialize_gpio_pin;
 use shared_utils::load_yaml::{
-    load_argon_config, ArchivedPowerScript, ArgonConfigValue, PowerScript,
+    ArgonConfigRKYV, ArgonConfigValue, ButtonAction, ButtonConfig, PowerScript,
+};
+use shared_utils::power_command::{
+    run_power_command, run_script, PowerCommand, PowerScriptConfigValue,
 };
-use shared_utils::rkyv::option::ArchivedOption;
-use shared_utils::rppal::gpio::{InputPin, Level};
-use std::path::Path;
-use std::process::{Child, Command};
-use std::time::Duration;
-use std::{fs, thread};
+use shared_utils::rkyv::{Deserialize, Infallible};
+use shared_utils::rppal::gpio::{Event, InputPin, Trigger};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+// How often simulated presses from the control socket are checked while waiting for the button.
+const SIMULATION_POLL_INTERVAL: Duration = Duration::from_millis(200);
 
 enum PowerOptions {
     Shutdown,
     Reboot,
+    ForcedShutdown,
+}
+
+enum ButtonEvent {
+    // The pulse width in microseconds.
+    Pulse(u32),
+    // No pulse arrived before the multi-press window closed.
+    WindowClosed,
+    Reload(Box<ArgonConfigRKYV>),
+}
+
+// The pin and the control socket are kept across config reloads.
+struct ButtonInput {
+    pin: InputPin,
+    simulated_pulses: Receiver<u32>,
+    last_pulse: Arc<Mutex<Option<u32>>>,
 }
 
 fn main() {
@@ -20,8 +47,15 @@ fn main() {
     match pin_result {
         Ok(pin) => {
             println!("GPIO Pin 4 initialized successfully");
-            load_argon_config(|final_argon_config| {
-                wait_shutdown_button_interrupt(pin, final_argon_config);
+            let (simulated_pulse_sender, simulated_pulses) = channel();
+            let mut button_input = ButtonInput {
+                pin,
+                simulated_pulses,
+                last_pulse: Arc::new(Mutex::new(None)),
+            };
+            start_control_socket(simulated_pulse_sender, Arc::clone(&button_input.last_pulse));
+            run_with_config_reload(|final_argon_config| {
+                wait_shutdown_button_interrupt(&mut button_input, final_argon_config)
             });
         }
         Err(e) => {
@@ -32,131 +66,373 @@ fn main() {
     println!("Shutdown button program finished");
 }
 
-fn wait_shutdown_button_interrupt(mut pin: InputPin, argon_config: ArgonConfigValue) {
-    let mut pulse_time: u16;
-    let selected_power_option: PowerOptions;
+fn wait_shutdown_button_interrupt(
+    button_input: &mut ButtonInput,
+    argon_config: ArgonConfigValue,
+) -> ServiceExit<()> {
+    let (watch_config, button_config): (bool, ButtonConfig) = match &argon_config {
+        ArgonConfigValue::Archived(archived_config) => (
+            archived_config.watch_config,
+            archived_config.button.deserialize(&mut Infallible).unwrap(),
+        ),
+        ArgonConfigValue::NonArchived(non_archived_config) => (
+            non_archived_config.watch_config,
+            non_archived_config.button.clone(),
+        ),
+    };
+    let mut config_watcher = ConfigWatcher::new(watch_config);
 
     loop {
-        pulse_time = 1;
-
         println!("Waiting shutdown button interrupt");
 
-        let pool_interrupt_result = pin.poll_interrupt(true, None);
-        match pool_interrupt_result {
-            Ok(Some(Level::High)) => {
-                thread::sleep(Duration::from_millis(10));
-                while pin.is_high() {
-                    thread::sleep(Duration::from_millis(10));
-                    pulse_time = pulse_time + 1;
+        // Pulses are collected while another one could still complete a multi-press gesture.
+        let mut pulses_ms: Vec<u32> = Vec::new();
+        let mut window_end: Option<Instant> = None;
+        loop {
+            match read_pulse(button_input, &mut config_watcher, window_end) {
+                ButtonEvent::Pulse(pulse_width) => {
+                    println!("Button pulse: {}us", pulse_width);
+                    *button_input.last_pulse.lock().unwrap() = Some(pulse_width);
+                    pulses_ms.push(pulse_milliseconds(pulse_width));
                 }
+                ButtonEvent::WindowClosed => break,
+                ButtonEvent::Reload(argon_config) => return ServiceExit::Reload(argon_config),
             }
-            Ok(Some(Level::Low)) => {
-                eprintln!("Interrupt finished with Low level, this should not happen");
+            if !button_config.expects_more_presses(&pulses_ms) {
+                break;
+            }
+            window_end =
+                Some(Instant::now() + Duration::from_millis(button_config.multi_press_window));
+        }
+
+        let selected_gesture = if let [pulse_ms] = pulses_ms[..] {
+            match button_config.gesture(pulse_ms) {
+                Some(gesture) => (gesture.action, &gesture.script, gesture.continue_listening),
+                None => {
+                    println!("No button gesture for a {}ms pulse", pulse_ms);
+                    continue;
+                }
+            }
+        } else {
+            match button_config.multi_press_gesture(&pulses_ms) {
+                Some(gesture) => (gesture.action, &gesture.script, gesture.continue_listening),
+                None => {
+                    println!("No button gesture for {} presses", pulses_ms.len());
+                    continue;
+                }
+            }
+        };
+
+        let (action, gesture_script, continue_listening) = selected_gesture;
+        let power_option = match action {
+            ButtonAction::Reboot => {
+                println!("Starting reboot...");
+                PowerOptions::Reboot
+            }
+            ButtonAction::Shutdown => {
+                println!("Starting shutdown...");
+                PowerOptions::Shutdown
+            }
+            ButtonAction::ForcedShutdown => {
+                println!("Starting forced shutdown...");
+                PowerOptions::ForcedShutdown
+            }
+            ButtonAction::RunScript => {
+                if let Some(script) = gesture_script {
+                    run_gesture_script(script);
+                }
+                continue;
+            }
+            ButtonAction::NextFanProfile => {
+                select_next_fan_profile();
+                continue;
+            }
+            ButtonAction::Ignore => {
+                println!("Ignoring the button gesture");
+                continue;
+            }
+        };
+
+        let power_command =
+            run_shutdown_or_reboot_command(power_option, gesture_script, &argon_config);
+        if !continue_listening {
+            // Exiting right away would let systemd stop the command with the service.
+            if let Some(power_command) = power_command {
+                power_command.wait();
+            }
+            return ServiceExit::Finished(());
+        }
+    }
+}
+
+// Measures the pulse width from the kernel timestamps of its rising and falling edges, so a busy
+// system doesn't stretch it. Simulated pulses and config reloads are checked between polls.
+fn read_pulse(
+    button_input: &mut ButtonInput,
+    config_watcher: &mut ConfigWatcher,
+    window_end: Option<Instant>,
+) -> ButtonEvent {
+    let pin = &mut button_input.pin;
+    let mut rising_edge: Option<Duration> = None;
+    // Interrupts that arrived since the previous pulse of a multi-press gesture are kept.
+    let mut reset = window_end.is_none();
+
+    loop {
+        // A pulse that already started is always measured, even if the window closes meanwhile.
+        let poll_timeout = match window_end {
+            Some(window_end) if rising_edge.is_none() => {
+                let remaining = window_end.saturating_duration_since(Instant::now());
+                if remaining.is_zero() {
+                    return ButtonEvent::WindowClosed;
+                }
+                remaining.min(SIMULATION_POLL_INTERVAL)
+            }
+            _ => SIMULATION_POLL_INTERVAL,
+        };
+        let pool_interrupt_result = pin.poll_interrupt(reset, Some(poll_timeout));
+        // Keep interrupts that arrived between polls.
+        reset = false;
+
+        match pool_interrupt_result {
+            Ok(Some(Event {
+                trigger: Trigger::RisingEdge,
+                timestamp,
+                ..
+            })) => {
+                rising_edge = Some(timestamp);
+            }
+            Ok(Some(Event {
+                trigger: Trigger::FallingEdge,
+                timestamp,
+                ..
+            })) => match rising_edge.take() {
+                Some(rising_timestamp) => {
+                    let pulse_width = timestamp.saturating_sub(rising_timestamp).as_micros();
+                    return ButtonEvent::Pulse(u32::try_from(pulse_width).unwrap_or(u32::MAX));
+                }
+                None => {
+                    eprintln!("Falling edge without a rising edge, ignoring it");
+                }
+            },
+            Ok(Some(event)) => {
+                eprintln!("Unexpected interrupt event: {:?}", event);
             }
             Ok(None) => {
-                eprintln!("Interrupt finished with a None result, this should not happen");
+                // The case pulses are much shorter than a poll, this is not one of them.
+                if rising_edge.take().is_some() {
+                    eprintln!(
+                        "The pulse lasted more than {:?}, ignoring it",
+                        SIMULATION_POLL_INTERVAL
+                    );
+                    continue;
+                }
+                if let Ok(simulated_pulse_width) = button_input.simulated_pulses.try_recv() {
+                    println!("Simulated button pulse: {}us", simulated_pulse_width);
+                    return ButtonEvent::Pulse(simulated_pulse_width);
+                }
+                if let Some(argon_config) = config_watcher.check() {
+                    return ButtonEvent::Reload(argon_config);
+                }
             }
             Err(e) => {
                 eprintln!("Interrupt Failed: {}", e);
+                rising_edge = None;
+                thread::sleep(SIMULATION_POLL_INTERVAL);
             }
         }
+    }
+}
 
-        if pulse_time >= 2 && pulse_time <= 3 {
-            println!("Starting reboot...");
-            selected_power_option = PowerOptions::Reboot;
-            break;
-        } else if pulse_time >= 4 && pulse_time <= 5 {
-            println!("Starting shutdown...");
-            selected_power_option = PowerOptions::Shutdown;
-            break;
-        } else if pulse_time >= 6 && pulse_time <= 7 {
-            println!("Starting forced shutdown...");
-            selected_power_option = PowerOptions::Shutdown;
-            break;
+fn start_control_socket(simulated_pulse_sender: Sender<u32>, last_pulse: Arc<Mutex<Option<u32>>>) {
+    let serve_result = serve_control_socket(BUTTON_SOCKET_PATH, move |command| match command {
+        ["status"] => {
+            let mut response = ok_response();
+            if let Some(pulse_width) = *last_pulse.lock().unwrap() {
+                response.push((String::from("last_pulse_us"), pulse_width.to_string()));
+            }
+            response
         }
+        ["simulate", gesture] => match simulated_pulse_width(gesture) {
+            Some(pulse_width) => match simulated_pulse_sender.send(pulse_width) {
+                Ok(_) => ok_response(),
+                Err(_) => error_response("The button is not listening anymore"),
+            },
+            None => error_response(&format!("Unknown button gesture: {}", gesture)),
+        },
+        _ => error_response(
+            "Unknown command. Use: status or simulate <double|long|forced|milliseconds>",
+        ),
+    });
+    if let Err(e) = serve_result {
+        eprintln!(
+            "Error starting the control socket on {}: {}. Continuing without it",
+            BUTTON_SOCKET_PATH, e
+        );
     }
+}
 
-    run_shutdown_or_reboot_command(selected_power_option, argon_config);
+// Rounded to the nearest millisecond, so a pulse a few microseconds short of a gesture bound still matches it.
+fn pulse_milliseconds(pulse_width: u32) -> u32 {
+    pulse_width.saturating_add(500) / 1000
+}
+
+// Pulse widths the case sends for each gesture, in microseconds.
+fn simulated_pulse_width(gesture: &str) -> Option<u32> {
+    match gesture {
+        "double" => Some(20_000),
+        "long" => Some(40_000),
+        "forced" => Some(60_000),
+        milliseconds => milliseconds
+            .parse::<u32>()
+            .ok()
+            .and_then(|milliseconds| milliseconds.checked_mul(1000)),
+    }
+}
+
+// Gesture scripts run in the background, the button keeps listening.
+fn run_gesture_script(script: &PowerScript) {
+    println!("Running button script {}", script.location);
+    match run_script(script) {
+        Ok(_) => {
+            println!("Button script started");
+        }
+        Err(e) => {
+            eprintln!("Cannot run button script {}: {}", script.location, e);
+        }
+    }
 }
 
-fn run_shutdown_or_reboot_command(power_option: PowerOptions, argon_config: ArgonConfigValue) {
+// The fan service owns the profile selection, so it is asked through its control socket.
+fn select_next_fan_profile() {
+    let response = match send_command(FAN_SOCKET_PATH, "next_profile") {
+        Ok(response) => response,
+        Err(e) => {
+            eprintln!(
+                "Cannot connect to the fan service on {}: {}",
+                FAN_SOCKET_PATH, e
+            );
+            return;
+        }
+    };
+
+    let field = |key: &str| {
+        response
+            .iter()
+            .find(|(response_key, _)| response_key == key)
+            .map(|(_, value)| value.as_str())
+    };
+    match field("status") {
+        Some("ok") => println!(
+            "Fan profile changed to {}",
+            field("profile").unwrap_or("auto")
+        ),
+        _ => eprintln!(
+            "Cannot change the fan profile: {}",
+            field("message").unwrap_or("Invalid response")
+        ),
+    }
+}
+
+// A script declared in the gesture replaces the one of the power option.
+fn run_shutdown_or_reboot_command(
+    power_option: PowerOptions,
+    gesture_script: &Option<PowerScript>,
+    argon_config: &ArgonConfigValue,
+) -> Option<PowerCommand> {
     let command_result = match power_option {
         PowerOptions::Shutdown => {
-            let shutdown_power_script = match argon_config {
-                ArgonConfigValue::Archived(archived_config) => {
+            let shutdown_power_script = match (gesture_script, argon_config) {
+                (Some(_), _) => PowerScriptConfigValue::NonArchivedPower(gesture_script.clone()),
+                (None, ArgonConfigValue::Archived(archived_config)) => {
                     PowerScriptConfigValue::ArchivedPower(&archived_config.shutdown_script)
                 }
-                ArgonConfigValue::NonArchived(non_archived_config) => {
-                    PowerScriptConfigValue::NonArchivedPower(non_archived_config.shutdown_script)
+                (None, ArgonConfigValue::NonArchived(non_archived_config)) => {
+                    PowerScriptConfigValue::NonArchivedPower(
+                        non_archived_config.shutdown_script.clone(),
+                    )
                 }
             };
-            run_power_command(shutdown_power_script, "shutdown", vec!["-h", "now"])
+            run_power_command(&shutdown_power_script, "shutdown", vec!["-h", "now"])
         }
         PowerOptions::Reboot => {
-            let reboot_power_script = match argon_config {
-                ArgonConfigValue::Archived(archived_config) => {
+            let reboot_power_script = match (gesture_script, argon_config) {
+                (Some(_), _) => PowerScriptConfigValue::NonArchivedPower(gesture_script.clone()),
+                (None, ArgonConfigValue::Archived(archived_config)) => {
                     PowerScriptConfigValue::ArchivedPower(&archived_config.reboot_script)
                 }
-                ArgonConfigValue::NonArchived(non_archived_config) => {
-                    PowerScriptConfigValue::NonArchivedPower(non_archived_config.reboot_script)
+                (None, ArgonConfigValue::NonArchived(non_archived_config)) => {
+                    PowerScriptConfigValue::NonArchivedPower(
+                        non_archived_config.reboot_script.clone(),
+                    )
+                }
+            };
+            run_power_command(&reboot_power_script, "reboot", vec![])
+        }
+        // The shutdown script is skipped, so a hung script can't block the power off.
+        PowerOptions::ForcedShutdown => {
+            let forced_shutdown_power_script = match (gesture_script, argon_config) {
+                (Some(_), _) => PowerScriptConfigValue::NonArchivedPower(gesture_script.clone()),
+                (None, ArgonConfigValue::Archived(archived_config)) => {
+                    PowerScriptConfigValue::ArchivedPower(&archived_config.forced_shutdown_script)
+                }
+                (None, ArgonConfigValue::NonArchived(non_archived_config)) => {
+                    PowerScriptConfigValue::NonArchivedPower(
+                        non_archived_config.forced_shutdown_script.clone(),
+                    )
                 }
             };
-            run_power_command(reboot_power_script, "reboot", vec![])
+            run_power_command(
+                &forced_shutdown_power_script,
+                "systemctl",
+                vec!["poweroff", "--force"],
+            )
         }
     };
 
     match command_result {
-        Ok(_) => {
+        Ok(power_command) => {
             println!("Power command executed");
+            Some(power_command)
         }
         Err(e) => {
             eprintln!("Cannot run power command: {}", e);
+            None
         }
     }
 }
 
-enum PowerScriptConfigValue<'a> {
-    ArchivedPower(&'a ArchivedOption<ArchivedPowerScript>),
-    NonArchivedPower(Option<PowerScript>),
-}
-fn run_power_command(
-    script_config_option: PowerScriptConfigValue,
-    fallback_command: &str,
-    fallback_args: Vec<&str>,
-) -> std::io::Result<Child> {
-    if let PowerScriptConfigValue::ArchivedPower(ArchivedOption::Some(archived_power_script)) =
-        script_config_option
-    {
-        let location = archived_power_script.location.as_str();
-        let args = archived_power_script.args.as_slice();
-        let path = Path::new(location);
-        match fs::metadata(path) {
-            Ok(metadata) => {
-                if metadata.is_file() {
-                    return Command::new(path).args(args).spawn();
-                }
-            }
-            Err(e) => {
-                eprintln!("No a valid script: {}", e);
-            }
-        }
-    } else if let PowerScriptConfigValue::NonArchivedPower(Some(non_archived_power_script)) =
-        script_config_option
-    {
-        let location = non_archived_power_script.location.as_str();
-        let args = non_archived_power_script.args.as_slice();
-        let path = Path::new(location);
-        match fs::metadata(path) {
-            Ok(metadata) => {
-                if metadata.is_file() {
-                    return Command::new(location).args(args).spawn();
-                }
-            }
-            Err(e) => {
-                eprintln!("No a valid script: {}", e);
-            }
-        }
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn default_action(pulse_width: u32) -> Option<ButtonAction> {
+        ButtonConfig::default()
+            .gesture(pulse_milliseconds(pulse_width))
+            .map(|gesture| gesture.action)
     }
 
-    Command::new(fallback_command).args(fallback_args).spawn()
+    #[test]
+    fn pulses_are_rounded_to_the_nearest_millisecond() {
+        assert_eq!(pulse_milliseconds(39_499), 39);
+        assert_eq!(pulse_milliseconds(39_500), 40);
+        assert_eq!(pulse_milliseconds(39_990), 40);
+        assert_eq!(pulse_milliseconds(u32::MAX), u32::MAX / 1000);
+    }
+
+    #[test]
+    fn pulses_just_short_of_a_bound_match_the_next_gesture() {
+        assert!(matches!(
+            default_action(29_990),
+            Some(ButtonAction::Shutdown)
+        ));
+        assert!(matches!(
+            default_action(39_990),
+            Some(ButtonAction::Shutdown)
+        ));
+        assert!(matches!(
+            default_action(49_990),
+            Some(ButtonAction::ForcedShutdown)
+        ));
+        assert!(matches!(default_action(19_900), Some(ButtonAction::Reboot)));
+    }
 }
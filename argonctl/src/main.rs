@@ -0,0 +1,230 @@
+use shared_utils::control_socket::{
+    error_response, send_command, ControlResponse, BUTTON_SOCKET_PATH, FAN_SOCKET_PATH,
+};
+use std::env;
+use std::process::ExitCode;
+
+const USAGE: &str = "Usage: argonctl [--json] <command>
+
+Commands:
+  status                                Show the fan and button services status
+  fan set <percent> [--for <duration>]  Force a fan speed, e.g. fan set 60 --for 10m
+  fan auto                              Go back to automatic fan control
+  profile <name>                        Select a fan profile, auto follows the schedule
+  button simulate <gesture>             Simulate a button press: double, long, forced or milliseconds";
+
+struct Request {
+    service: &'static str,
+    socket_path: &'static str,
+    command: String,
+}
+impl Request {
+    fn fan(command: String) -> Request {
+        Request {
+            service: "fan",
+            socket_path: FAN_SOCKET_PATH,
+            command,
+        }
+    }
+
+    fn button(command: String) -> Request {
+        Request {
+            service: "button",
+            socket_path: BUTTON_SOCKET_PATH,
+            command,
+        }
+    }
+}
+
+fn main() -> ExitCode {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let json_output = args.iter().any(|arg| arg == "--json");
+    let args: Vec<&str> = args
+        .iter()
+        .map(String::as_str)
+        .filter(|&arg| arg != "--json")
+        .collect();
+
+    let requests = match args.as_slice() {
+        ["status"] => vec![
+            Request::fan(String::from("status")),
+            Request::button(String::from("status")),
+        ],
+        ["fan", "set", speed] => vec![Request::fan(format!("set {}", speed))],
+        ["fan", "set", speed, "--for", duration] => {
+            vec![Request::fan(format!("set {} for {}", speed, duration))]
+        }
+        ["fan", "auto"] => vec![Request::fan(String::from("auto"))],
+        ["profile", profile_name] => vec![Request::fan(format!("profile {}", profile_name))],
+        ["button", "simulate", gesture] => vec![Request::button(format!("simulate {}", gesture))],
+        _ => {
+            eprintln!("{}", USAGE);
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let responses: Vec<(&str, ControlResponse)> = requests
+        .iter()
+        .map(|request| {
+            let response =
+                send_command(request.socket_path, &request.command).unwrap_or_else(|e| {
+                    error_response(&format!(
+                        "Cannot connect to {}: {}. Is the service running?",
+                        request.socket_path, e
+                    ))
+                });
+            (request.service, response)
+        })
+        .collect();
+
+    if json_output {
+        print_json(&responses);
+    } else {
+        print_human(&responses);
+    }
+
+    let all_ok = responses
+        .iter()
+        .all(|(_, response)| field(response, "status") == Some("ok"));
+    if all_ok {
+        ExitCode::SUCCESS
+    } else {
+        ExitCode::FAILURE
+    }
+}
+
+fn field<'a>(response: &'a ControlResponse, key: &str) -> Option<&'a str> {
+    response
+        .iter()
+        .find(|(response_key, _)| response_key == key)
+        .map(|(_, value)| value.as_str())
+}
+
+fn print_human(responses: &[(&str, ControlResponse)]) {
+    for (service, response) in responses {
+        if field(response, "status") != Some("ok") {
+            eprintln!(
+                "{}: {}",
+                service,
+                field(response, "message").unwrap_or("Invalid response")
+            );
+            continue;
+        }
+
+        let fields: Vec<&(String, String)> =
+            response.iter().filter(|(key, _)| key != "status").collect();
+        if fields.is_empty() {
+            println!("{}: ok", service);
+            continue;
+        }
+        println!("{}:", service);
+        for (key, value) in fields {
+            println!("  {}: {}", key, value);
+        }
+    }
+}
+
+// Fields the services send as numbers, everything else is a string. Temperatures are `temperature.<sensor>`.
+const NUMBER_FIELDS: [&str; 3] = ["fan_speed", "manual_remaining", "last_pulse_us"];
+const NUMBER_FIELD_PREFIX: &str = "temperature.";
+
+// Every service is a JSON object of its response fields, numbers are not quoted.
+fn print_json(responses: &[(&str, ControlResponse)]) {
+    println!("{}", format_json(responses));
+}
+
+fn format_json(responses: &[(&str, ControlResponse)]) -> String {
+    let services: Vec<String> = responses
+        .iter()
+        .map(|(service, response)| {
+            let fields: Vec<String> = response
+                .iter()
+                .map(|(key, value)| {
+                    let value = if is_number_field(key) && is_json_number(value) {
+                        value.clone()
+                    } else {
+                        json_string(value)
+                    };
+                    format!("{}:{}", json_string(key), value)
+                })
+                .collect();
+            format!("{}:{{{}}}", json_string(service), fields.join(","))
+        })
+        .collect();
+    format!("{{{}}}", services.join(","))
+}
+
+fn is_number_field(key: &str) -> bool {
+    NUMBER_FIELDS.contains(&key) || key.starts_with(NUMBER_FIELD_PREFIX)
+}
+
+// Integers and decimals, as sent by the services. A value that is not valid JSON is quoted instead.
+fn is_json_number(value: &str) -> bool {
+    let digits = value.strip_prefix('-').unwrap_or(value);
+    let (integer, fraction) = digits.split_once('.').unwrap_or((digits, "0"));
+    let all_digits =
+        |part: &str| !part.is_empty() && part.chars().all(|character| character.is_ascii_digit());
+    all_digits(integer) && all_digits(fraction) && (integer == "0" || !integer.starts_with('0'))
+}
+
+fn json_string(value: &str) -> String {
+    let mut json = String::from("\"");
+    for character in value.chars() {
+        match character {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            '\n' => json.push_str("\\n"),
+            character if character.is_control() => {
+                json.push_str(&format!("\\u{:04x}", character as u32))
+            }
+            character => json.push(character),
+        }
+    }
+    json.push('"');
+    json
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn json_response(fields: &[(&str, &str)]) -> String {
+        let response: ControlResponse = fields
+            .iter()
+            .map(|&(key, value)| (String::from(key), String::from(value)))
+            .collect();
+        format_json(&[("fan", response)])
+    }
+
+    #[test]
+    fn numbers_are_decided_by_field() {
+        let json = json_response(&[
+            ("status", "ok"),
+            ("fan_speed", "40"),
+            ("profile", "60"),
+            ("temperature.main", "52.3"),
+        ]);
+
+        assert_eq!(
+            json,
+            r#"{"fan":{"status":"ok","fan_speed":40,"profile":"60","temperature.main":52.3}}"#
+        );
+    }
+
+    #[test]
+    fn invalid_numbers_are_quoted() {
+        let json = json_response(&[("profile", "007"), ("fan_speed", "007")]);
+
+        assert_eq!(json, r#"{"fan":{"profile":"007","fan_speed":"007"}}"#);
+    }
+
+    #[test]
+    fn json_numbers() {
+        for number in ["0", "40", "-3", "52.3", "0.5"] {
+            assert!(is_json_number(number), "{}", number);
+        }
+        for not_number in ["", "007", "1.", ".5", "1e3", "abc", "-"] {
+            assert!(!is_json_number(not_number), "{}", not_number);
+        }
+    }
+}
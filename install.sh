@@ -11,6 +11,8 @@ shutdown_button_service="$shutdown_button_binary_name.service"
 
 shutdown_binary_name="argon_shutdown"
 
+cli_binary_name="argonctl"
+
 
 # Check if raspi-config is available, this package should be available in most raspberry pi distributions.
 command -v raspi-config &> /dev/null
@@ -62,6 +64,10 @@ sudo cp ./$fan_binary_name /usr/bin/
 sudo chmod 755 ./$shutdown_button_binary_name
 sudo cp ./$shutdown_button_binary_name /usr/bin/
 
+# Copy command line client executable
+sudo chmod 755 ./$cli_binary_name
+sudo cp ./$cli_binary_name /usr/bin/
+
 # Copy shutdown executable
 sudo chmod 755 ./$shutdown_binary_name
 sudo cp ./$shutdown_binary_name /lib/systemd/system-shutdown/
@@ -0,0 +1,94 @@
+use crate::load_yaml::{
+    apply_argon_config, load_argon_config, read_yaml_config, yaml_config_modified, ArgonConfigRKYV,
+    ArgonConfigValue,
+};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::SystemTime;
+
+static RELOAD_REQUESTED: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn request_reload(_signal: libc::c_int) {
+    RELOAD_REQUESTED.store(true, Ordering::SeqCst);
+}
+
+pub enum ServiceExit<T> {
+    Finished(T),
+    // The service stopped to run again with this config.
+    Reload(Box<ArgonConfigRKYV>),
+}
+
+// Runs the service with the loaded config, and again every time it returns a reloaded config.
+pub fn run_with_config_reload<T, F>(mut run_service: F) -> Option<T>
+where
+    F: FnMut(ArgonConfigValue) -> ServiceExit<T>,
+{
+    // SAFETY: the handler only stores into an atomic.
+    unsafe {
+        let handler: extern "C" fn(libc::c_int) = request_reload;
+        libc::signal(libc::SIGHUP, handler as libc::sighandler_t);
+    }
+
+    let mut reloaded_config: Option<Box<ArgonConfigRKYV>> = None;
+    loop {
+        let mut service_exit = None;
+        let on_config_ready =
+            |argon_config: ArgonConfigValue| service_exit = Some(run_service(argon_config));
+        match reloaded_config.take() {
+            Some(argon_config) => apply_argon_config(*argon_config, on_config_ready),
+            None => load_argon_config(on_config_ready),
+        }
+
+        match service_exit? {
+            ServiceExit::Finished(result) => return Some(result),
+            ServiceExit::Reload(argon_config) => {
+                println!("Config reloaded");
+                reloaded_config = Some(argon_config);
+            }
+        }
+    }
+}
+
+// Checked by the service loops. A reload is requested with SIGHUP, or by changing the
+// YAML config file when `watch_config` is enabled.
+pub struct ConfigWatcher {
+    watch_file: bool,
+    last_modified: Option<SystemTime>,
+}
+impl ConfigWatcher {
+    pub fn new(watch_file: bool) -> ConfigWatcher {
+        ConfigWatcher {
+            watch_file,
+            last_modified: yaml_config_modified().ok(),
+        }
+    }
+
+    // Returns the new config only when it is valid, otherwise the service keeps the current one.
+    pub fn check(&mut self) -> Option<Box<ArgonConfigRKYV>> {
+        let signal_requested = RELOAD_REQUESTED.swap(false, Ordering::SeqCst);
+        let file_changed = self.watch_file && self.file_changed();
+        if !signal_requested && !file_changed {
+            return None;
+        }
+
+        println!("Reloading YAML config...");
+        match read_yaml_config() {
+            Ok(argon_config) => Some(Box::new(argon_config)),
+            Err(e) => {
+                eprintln!(
+                    "There is an error with your YAML config, keeping the current one: {}",
+                    e
+                );
+                None
+            }
+        }
+    }
+
+    fn file_changed(&mut self) -> bool {
+        let modified = yaml_config_modified().ok();
+        if modified == self.last_modified {
+            return false;
+        }
+        self.last_modified = modified;
+        true
+    }
+}
@@ -0,0 +1,115 @@
+use std::fs::{create_dir_all, remove_file};
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::Path;
+use std::thread;
+use std::time::Duration;
+
+pub const FAN_SOCKET_PATH: &str = "/run/argonone/argon_fan.sock";
+pub const BUTTON_SOCKET_PATH: &str = "/run/argonone/argon_shutdown_button.sock";
+
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(5);
+
+// Responses are `key=value` lines, with a `status` of `ok` or `error`.
+pub type ControlResponse = Vec<(String, String)>;
+
+pub fn ok_response() -> ControlResponse {
+    vec![(String::from("status"), String::from("ok"))]
+}
+
+pub fn error_response(message: &str) -> ControlResponse {
+    vec![
+        (String::from("status"), String::from("error")),
+        (String::from("message"), String::from(message)),
+    ]
+}
+
+pub fn format_response(response: &ControlResponse) -> String {
+    response
+        .iter()
+        .map(|(key, value)| format!("{}={}\n", key, value))
+        .collect()
+}
+
+pub fn parse_response(response: &str) -> ControlResponse {
+    response
+        .lines()
+        .filter_map(|line| line.split_once('='))
+        .map(|(key, value)| (String::from(key), String::from(value)))
+        .collect()
+}
+
+// Durations like `90`, `30s`, `10m` or `1h`. Plain numbers are seconds.
+pub fn parse_duration(duration: &str) -> Option<Duration> {
+    let duration = duration.trim();
+    let (value, unit_seconds) = match duration.char_indices().last()? {
+        (index, 's') => (&duration[..index], 1),
+        (index, 'm') => (&duration[..index], 60),
+        (index, 'h') => (&duration[..index], 60 * 60),
+        _ => (duration, 1),
+    };
+    let value: u64 = value.parse().ok()?;
+    Some(Duration::from_secs(value.checked_mul(unit_seconds)?))
+}
+
+// Answers one command per connection from a background thread.
+pub fn serve_control_socket<H>(socket_path: &str, handler: H) -> io::Result<()>
+where
+    H: Fn(&[&str]) -> ControlResponse + Send + 'static,
+{
+    let listener = bind_socket(socket_path)?;
+    println!("Listening for commands on {}", socket_path);
+
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            match stream {
+                Ok(stream) => {
+                    if let Err(e) = handle_client(stream, &handler) {
+                        eprintln!("Control socket client error: {}", e);
+                    }
+                }
+                Err(e) => eprintln!("Control socket error: {}", e),
+            }
+        }
+    });
+    Ok(())
+}
+
+fn bind_socket(socket_path: &str) -> io::Result<UnixListener> {
+    let socket_path = Path::new(socket_path);
+    if let Some(socket_directory) = socket_path.parent() {
+        create_dir_all(socket_directory)?;
+    }
+    // A socket left behind by a previous run would make bind fail.
+    match remove_file(socket_path) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+        _ => {}
+    }
+    UnixListener::bind(socket_path)
+}
+
+fn handle_client<H>(stream: UnixStream, handler: &H) -> io::Result<()>
+where
+    H: Fn(&[&str]) -> ControlResponse,
+{
+    stream.set_read_timeout(Some(CLIENT_TIMEOUT))?;
+    stream.set_write_timeout(Some(CLIENT_TIMEOUT))?;
+
+    let mut command = String::new();
+    BufReader::new(&stream).read_line(&mut command)?;
+    let command: Vec<&str> = command.split_whitespace().collect();
+
+    let response = handler(&command);
+    (&stream).write_all(format_response(&response).as_bytes())
+}
+
+pub fn send_command(socket_path: &str, command: &str) -> io::Result<ControlResponse> {
+    let mut stream = UnixStream::connect(socket_path)?;
+    stream.set_read_timeout(Some(CLIENT_TIMEOUT))?;
+    stream.set_write_timeout(Some(CLIENT_TIMEOUT))?;
+
+    stream.write_all(format!("{}\n", command).as_bytes())?;
+    let mut response = String::new();
+    stream.read_to_string(&mut response)?;
+    Ok(parse_response(&response))
+}
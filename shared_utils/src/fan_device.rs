@@ -0,0 +1,90 @@
+use crate::initialize_device::{initialize_i2c, CUT_POWER_BYTE, TURN_OFF_FAN};
+use rppal::i2c::{Error as I2cError, I2c};
+use std::io;
+use std::sync::{Arc, Mutex};
+
+pub const MAX_FAN_SPEED: u8 = 100;
+
+pub trait FanDevice {
+    type Error: std::error::Error + 'static;
+
+    fn send_byte(&mut self, byte: u8) -> Result<(), Self::Error>;
+
+    // Speeds above 100 are clamped, otherwise 0xff would be read as a power cut by the MCU.
+    fn set_speed(&mut self, speed: u8) -> Result<(), Self::Error> {
+        self.send_byte(speed.min(MAX_FAN_SPEED))
+    }
+
+    fn turn_off(&mut self) -> Result<(), Self::Error> {
+        self.send_byte(TURN_OFF_FAN)
+    }
+
+    fn power_cut(&mut self) -> Result<(), Self::Error> {
+        self.send_byte(CUT_POWER_BYTE)
+    }
+}
+
+pub struct I2cFanDevice {
+    i2c: I2c,
+}
+impl I2cFanDevice {
+    pub fn new() -> Result<I2cFanDevice, I2cError> {
+        initialize_i2c().map(|i2c| I2cFanDevice { i2c })
+    }
+}
+impl FanDevice for I2cFanDevice {
+    type Error = I2cError;
+
+    fn send_byte(&mut self, byte: u8) -> Result<(), Self::Error> {
+        self.i2c.smbus_send_byte(byte)
+    }
+}
+
+#[derive(Default, Debug)]
+struct MockFanDeviceState {
+    written_bytes: Vec<u8>,
+    pending_failures: usize,
+}
+
+#[derive(Clone, Default, Debug)]
+pub struct MockFanDevice {
+    state: Arc<Mutex<MockFanDeviceState>>,
+}
+impl MockFanDevice {
+    pub fn new() -> MockFanDevice {
+        MockFanDevice::default()
+    }
+
+    pub fn written_bytes(&self) -> Vec<u8> {
+        self.state.lock().unwrap().written_bytes.clone()
+    }
+
+    pub fn last_byte(&self) -> Option<u8> {
+        self.state.lock().unwrap().written_bytes.last().copied()
+    }
+
+    pub fn clear(&self) {
+        self.state.lock().unwrap().written_bytes.clear();
+    }
+
+    // The next `count` writes fail with an I/O error and are not recorded.
+    pub fn fail_next_writes(&self, count: usize) {
+        self.state.lock().unwrap().pending_failures = count;
+    }
+}
+impl FanDevice for MockFanDevice {
+    type Error = io::Error;
+
+    fn send_byte(&mut self, byte: u8) -> Result<(), Self::Error> {
+        let mut state = self.state.lock().unwrap();
+        if state.pending_failures > 0 {
+            state.pending_failures -= 1;
+            return Err(io::Error::new(
+                io::ErrorKind::BrokenPipe,
+                "Mock fan device write failure",
+            ));
+        }
+        state.written_bytes.push(byte);
+        Ok(())
+    }
+}
@@ -14,6 +14,6 @@ pub fn initialize_i2c() -> Result<i2c::I2c, i2c::Error> {
 pub fn initialize_gpio_pin() -> Result<gpio::InputPin, gpio::Error> {
     let gpio = gpio::Gpio::new()?;
     let mut pin = gpio.get(4)?.into_input_pulldown();
-    pin.set_interrupt(gpio::Trigger::RisingEdge)?;
+    pin.set_interrupt(gpio::Trigger::Both, None)?;
     Ok(pin)
 }
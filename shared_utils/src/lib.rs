@@ -1,6 +1,11 @@
+pub mod config_reload;
+pub mod control_socket;
+pub mod fan_device;
 pub mod initialize_device;
 pub mod load_yaml;
 mod mapper;
+pub mod power_command;
+pub mod temperature_source;
 
 pub use rkyv;
 pub use rppal;
@@ -4,30 +4,438 @@ use rkyv::validation::CheckTypeError;
 use rkyv::{AlignedVec, Archive, Deserialize as RkyvDeserialize, Serialize as RkyvSerialize};
 use serde::{Deserialize as SerdeDeserialize, Deserializer as SerdeDeserializer};
 use std::cmp::Ordering;
-use std::collections::HashMap;
+use std::collections::BTreeMap;
 use std::fs::{metadata, read, remove_file, write};
 use std::io;
+use std::time::{Duration, SystemTime};
 
 const YAML_CONFIG_PATH: &str = "/etc/argonone/argon_services_config.yaml";
 pub const RKYV_CONFIG_PATH: &str = "/etc/argonone/argon_services_config.rkyv";
 
 pub type TempMatrixYAML = Vec<[u8; 2]>;
-pub type TempMatrixRKYV = HashMap<u8, u8>;
 
-#[derive(SerdeDeserialize, RkyvDeserialize, RkyvSerialize, Archive, Debug)]
+#[derive(
+    SerdeDeserialize, RkyvDeserialize, RkyvSerialize, Archive, Debug, Clone, Copy, Default,
+)]
+#[serde(rename_all = "snake_case")]
+#[archive(check_bytes)]
+#[archive_attr(derive(Debug))]
+pub enum Interpolation {
+    Step,
+    #[default]
+    Linear,
+    MonotoneCubic,
+}
+
+#[derive(RkyvDeserialize, RkyvSerialize, Archive, Debug, Clone)]
+#[archive(check_bytes)]
+#[archive_attr(derive(Debug))]
+pub struct FanCurve {
+    pub interpolation: Interpolation,
+    pub points: Vec<[u8; 2]>,
+    pub tangents: Vec<f32>,
+}
+
+#[derive(SerdeDeserialize, Debug)]
+pub struct HysteresisYAML {
+    pub rise: Option<f32>,
+    pub fall: Option<f32>,
+    pub amount: Option<u8>,
+    pub only_way_down: Option<bool>,
+}
+
+// The matrix speed is updated when the temperature rises more than `rise`
+// or falls more than `fall` since the last update.
+#[derive(RkyvDeserialize, RkyvSerialize, Archive, Debug, Clone)]
 #[archive(check_bytes)]
 #[archive_attr(derive(Debug))]
 pub struct Hysteresis {
-    pub amount: u8,
-    pub only_way_down: bool,
+    pub rise: f32,
+    pub fall: f32,
+}
+impl Default for Hysteresis {
+    fn default() -> Self {
+        Hysteresis {
+            rise: 0f32,
+            fall: 4f32,
+        }
+    }
+}
+impl Hysteresis {
+    pub fn exceeded(&self, temperature_delta: f32) -> bool {
+        temperature_delta > self.rise || -temperature_delta > self.fall
+    }
+}
+#[derive(SerdeDeserialize, RkyvDeserialize, RkyvSerialize, Archive, Debug, Clone)]
+#[serde(default)]
+#[archive(check_bytes)]
+#[archive_attr(derive(Debug))]
+pub struct RetryPolicy {
+    pub initial_delay: u64,
+    pub max_delay: u64,
+    pub multiplier: f32,
+    pub max_attempts: u32,
+}
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            initial_delay: 10000u64,
+            max_delay: 300000u64,
+            multiplier: 2.0f32,
+            max_attempts: 5u32,
+        }
+    }
+}
+impl RetryPolicy {
+    // A max_attempts of 0 means retrying forever.
+    pub fn should_give_up(&self, failures: u32) -> bool {
+        self.max_attempts != 0 && failures >= self.max_attempts
+    }
+
+    pub fn delay(&self, failures: u32) -> Duration {
+        let exponent = failures.saturating_sub(1).min(i32::MAX as u32) as i32;
+        let delay = self.initial_delay as f64 * (self.multiplier as f64).powi(exponent);
+        Duration::from_millis(delay.min(self.max_delay as f64) as u64)
+    }
+}
+
+#[derive(SerdeDeserialize, RkyvDeserialize, RkyvSerialize, Archive, Debug, Clone, Default)]
+#[serde(tag = "type", rename_all = "snake_case")]
+#[archive(check_bytes)]
+#[archive_attr(derive(Debug))]
+pub enum TemperatureSourceConfig {
+    #[default]
+    Systemstat,
+    ThermalZone {
+        #[serde(default)]
+        zone: u8,
+    },
+    Hwmon {
+        path: String,
+    },
+    Fixed {
+        values: Vec<f32>,
+    },
+}
+
+#[derive(SerdeDeserialize, RkyvDeserialize, RkyvSerialize, Archive, Debug, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+#[archive(check_bytes)]
+#[archive_attr(derive(Debug))]
+pub enum CriticalAction {
+    ShutdownScript,
+    Shutdown,
+}
+fn default_critical_action() -> CriticalAction {
+    CriticalAction::ShutdownScript
+}
+
+#[derive(SerdeDeserialize, RkyvDeserialize, RkyvSerialize, Archive, Debug, Clone)]
+#[archive(check_bytes)]
+#[archive_attr(derive(Debug))]
+pub struct CriticalTemperature {
+    pub temperature: u8,
+    #[serde(default)]
+    pub duration: u64,
+    #[serde(default = "default_critical_action")]
+    pub action: CriticalAction,
+}
+
+#[derive(SerdeDeserialize, RkyvDeserialize, RkyvSerialize, Archive, Debug, Clone)]
+#[archive(check_bytes)]
+#[archive_attr(derive(Debug))]
+pub struct SpinUp {
+    #[serde(deserialize_with = "deserialize_fan_speed")]
+    pub speed: u8,
+    pub duration: u64,
+    #[serde(default, deserialize_with = "deserialize_optional_fan_speed")]
+    pub threshold: Option<u8>,
 }
+impl SpinUp {
+    // Without a threshold, any speed lower than the kick speed needs a kick.
+    pub fn needs_kick(&self, fan_speed: u8) -> bool {
+        fan_speed > 0 && fan_speed < self.threshold.unwrap_or(self.speed)
+    }
+}
+
+#[derive(SerdeDeserialize, RkyvDeserialize, RkyvSerialize, Archive, Debug, Clone, Default)]
+#[archive(check_bytes)]
+#[archive_attr(derive(Debug))]
+pub struct SlewRate {
+    pub up: Option<u8>,
+    pub down: Option<u8>,
+}
+
+#[derive(SerdeDeserialize, RkyvDeserialize, RkyvSerialize, Archive, Debug, Clone, Default)]
+#[serde(tag = "type", rename_all = "snake_case")]
+#[archive(check_bytes)]
+#[archive_attr(derive(Debug))]
+pub enum FilterConfig {
+    #[default]
+    None,
+    Ema {
+        alpha: f32,
+    },
+    Median {
+        window: u8,
+    },
+    Mean {
+        samples: u8,
+        sample_interval: u64,
+    },
+}
+
+#[derive(SerdeDeserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum FanMode {
+    #[default]
+    Matrix,
+    Pid,
+}
+
+#[derive(SerdeDeserialize, RkyvDeserialize, RkyvSerialize, Archive, Debug, Clone)]
+#[archive(check_bytes)]
+#[archive_attr(derive(Debug))]
+pub struct PidConfig {
+    pub target: f32,
+    pub kp: f32,
+    #[serde(default)]
+    pub ki: f32,
+    #[serde(default)]
+    pub kd: f32,
+    #[serde(default, deserialize_with = "deserialize_fan_speed")]
+    pub min_speed: u8,
+    #[serde(
+        default = "default_max_speed",
+        deserialize_with = "deserialize_fan_speed"
+    )]
+    pub max_speed: u8,
+}
+fn default_max_speed() -> u8 {
+    100
+}
+
+#[derive(RkyvDeserialize, RkyvSerialize, Archive, Debug)]
+#[archive(check_bytes)]
+#[archive_attr(derive(Debug))]
+pub enum FanControl {
+    Matrix(FanCurve),
+    Pid(PidConfig),
+}
+
 #[derive(SerdeDeserialize, Debug)]
-pub struct FanConfigYAML {
-    pub interval: u64,
-    pub hysteresis: Hysteresis,
+pub struct SensorYAML {
+    pub name: String,
+    #[serde(default)]
+    pub temperature_source: TemperatureSourceConfig,
+    pub interpolation: Option<Interpolation>,
     #[serde(deserialize_with = "deserialize_matrix")]
     pub matrix: TempMatrixYAML,
 }
+
+#[derive(SerdeDeserialize, Debug)]
+pub struct FanProfileYAML {
+    pub name: String,
+    pub interpolation: Option<Interpolation>,
+    #[serde(deserialize_with = "deserialize_matrix")]
+    pub matrix: TempMatrixYAML,
+    #[serde(default, deserialize_with = "deserialize_optional_fan_speed")]
+    pub max_speed: Option<u8>,
+    pub critical_temperature: Option<u8>,
+}
+
+#[derive(RkyvDeserialize, RkyvSerialize, Archive, Debug)]
+#[archive(check_bytes)]
+#[archive_attr(derive(Debug))]
+pub struct FanProfileRKYV {
+    pub name: String,
+    pub curve: FanCurve,
+    pub max_speed: Option<u8>,
+    pub critical_temperature: Option<u8>,
+}
+
+#[derive(SerdeDeserialize, Debug, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum Weekday {
+    Sun,
+    Mon,
+    Tue,
+    Wed,
+    Thu,
+    Fri,
+    Sat,
+}
+
+#[derive(SerdeDeserialize, Debug)]
+pub struct ScheduleEntryYAML {
+    pub profile: String,
+    #[serde(deserialize_with = "deserialize_time_window")]
+    pub time: (u16, u16),
+    #[serde(default)]
+    pub days: Vec<Weekday>,
+}
+
+// `start` and `end` are minutes since midnight, `days` is a bitmask with Sunday as bit 0.
+#[derive(RkyvDeserialize, RkyvSerialize, Archive, Debug, Clone)]
+#[archive(check_bytes)]
+#[archive_attr(derive(Debug))]
+pub struct ScheduleEntry {
+    pub profile: String,
+    pub start: u16,
+    pub end: u16,
+    pub days: u8,
+}
+impl ScheduleEntry {
+    // Windows like 22:00-07:00 go past midnight, the part after midnight
+    // belongs to the day the window started. An empty window lasts all day.
+    pub fn is_active(&self, weekday: u8, minutes: u16) -> bool {
+        let previous_weekday = (weekday + 6) % 7;
+        let on_day = |day: u8| self.days & (1 << day) != 0;
+
+        match self.start.cmp(&self.end) {
+            Ordering::Equal => on_day(weekday),
+            Ordering::Less => on_day(weekday) && minutes >= self.start && minutes < self.end,
+            Ordering::Greater => {
+                on_day(weekday) && minutes >= self.start
+                    || on_day(previous_weekday) && minutes < self.end
+            }
+        }
+    }
+}
+
+#[derive(SerdeDeserialize, Debug)]
+pub struct FanConfigYAML {
+    pub interval: u64,
+    #[serde(default, deserialize_with = "deserialize_optional_hysteresis")]
+    pub hysteresis: Option<Hysteresis>,
+    #[serde(default)]
+    pub temperature_source: TemperatureSourceConfig,
+    #[serde(default)]
+    pub filter: FilterConfig,
+    #[serde(default, deserialize_with = "deserialize_optional_matrix")]
+    pub matrix: Option<TempMatrixYAML>,
+    #[serde(default)]
+    pub interpolation: Interpolation,
+    #[serde(default)]
+    pub mode: FanMode,
+    pub pid: Option<PidConfig>,
+    #[serde(default)]
+    pub sensors: Vec<SensorYAML>,
+    #[serde(default)]
+    pub profiles: Vec<FanProfileYAML>,
+    #[serde(default)]
+    pub schedule: Vec<ScheduleEntryYAML>,
+    #[serde(
+        default = "default_failsafe_speed",
+        deserialize_with = "deserialize_fan_speed"
+    )]
+    pub failsafe_speed: u8,
+    pub critical: Option<CriticalTemperature>,
+    #[serde(default)]
+    pub retry: RetryPolicy,
+    pub refresh_interval: Option<u64>,
+    #[serde(default)]
+    pub slew_rate: SlewRate,
+    #[serde(default)]
+    pub min_hold_time: u64,
+    pub spin_up: Option<SpinUp>,
+    #[serde(default, deserialize_with = "deserialize_fan_speed")]
+    pub min_running_speed: u8,
+}
+fn default_failsafe_speed() -> u8 {
+    100
+}
+fn deserialize_fan_speed<'de, D>(deserializer: D) -> Result<u8, D::Error>
+where
+    D: SerdeDeserializer<'de>,
+{
+    let fan_speed: u8 = SerdeDeserialize::deserialize(deserializer)?;
+
+    if fan_speed > 100 {
+        return Err(serde::de::Error::custom(format!(
+            "Fan speed must be within 0-100. Found: {}",
+            fan_speed
+        )));
+    }
+
+    Ok(fan_speed)
+}
+fn deserialize_hysteresis<'de, D>(deserializer: D) -> Result<Hysteresis, D::Error>
+where
+    D: SerdeDeserializer<'de>,
+{
+    let hysteresis: HysteresisYAML = SerdeDeserialize::deserialize(deserializer)?;
+
+    match hysteresis {
+        HysteresisYAML {
+            rise: Some(rise),
+            fall: Some(fall),
+            amount: None,
+            only_way_down: None,
+        } => {
+            let valid_amount = |amount: f32| amount.is_finite() && amount >= 0.0;
+            if !(valid_amount(rise) && valid_amount(fall)) {
+                return Err(serde::de::Error::custom(format!(
+                    "Hysteresis rise and fall must be positive numbers. Found: rise {}, fall {}",
+                    rise, fall
+                )));
+            }
+            Ok(Hysteresis { rise, fall })
+        }
+        // Legacy format: `only_way_down` updates the speed on any temperature rise.
+        HysteresisYAML {
+            rise: None,
+            fall: None,
+            amount: Some(amount),
+            only_way_down,
+        } => Ok(Hysteresis {
+            rise: if only_way_down.unwrap_or(false) {
+                0.0
+            } else {
+                amount as f32
+            },
+            fall: amount as f32,
+        }),
+        _ => Err(serde::de::Error::custom(
+            "Hysteresis must set either both rise and fall, or amount and only_way_down",
+        )),
+    }
+}
+fn deserialize_optional_hysteresis<'de, D>(deserializer: D) -> Result<Option<Hysteresis>, D::Error>
+where
+    D: SerdeDeserializer<'de>,
+{
+    deserialize_hysteresis(deserializer).map(Some)
+}
+fn deserialize_time_window<'de, D>(deserializer: D) -> Result<(u16, u16), D::Error>
+where
+    D: SerdeDeserializer<'de>,
+{
+    let time_window: String = SerdeDeserialize::deserialize(deserializer)?;
+
+    let parse_time = |time: &str| -> Option<u16> {
+        let (hours, minutes) = time.trim().split_once(':')?;
+        let hours: u16 = hours.parse().ok()?;
+        let minutes: u16 = minutes.parse().ok()?;
+        (hours < 24 && minutes < 60).then_some(hours * 60 + minutes)
+    };
+
+    time_window
+        .split_once('-')
+        .and_then(|(start, end)| Some((parse_time(start)?, parse_time(end)?)))
+        .ok_or_else(|| {
+            serde::de::Error::custom(format!(
+                "The schedule time must be a HH:MM-HH:MM window. Found: {}",
+                time_window
+            ))
+        })
+}
+fn deserialize_optional_fan_speed<'de, D>(deserializer: D) -> Result<Option<u8>, D::Error>
+where
+    D: SerdeDeserializer<'de>,
+{
+    deserialize_fan_speed(deserializer).map(Some)
+}
 fn deserialize_matrix<'de, D>(deserializer: D) -> Result<TempMatrixYAML, D::Error>
 where
     D: SerdeDeserializer<'de>,
@@ -54,6 +462,21 @@ where
 
     Ok(data)
 }
+fn deserialize_optional_matrix<'de, D>(deserializer: D) -> Result<Option<TempMatrixYAML>, D::Error>
+where
+    D: SerdeDeserializer<'de>,
+{
+    deserialize_matrix(deserializer).map(Some)
+}
+
+#[derive(RkyvDeserialize, RkyvSerialize, Archive, Debug)]
+#[archive(check_bytes)]
+#[archive_attr(derive(Debug))]
+pub struct SensorRKYV {
+    pub name: String,
+    pub temperature_source: TemperatureSourceConfig,
+    pub control: FanControl,
+}
 
 #[derive(RkyvDeserialize, RkyvSerialize, Archive, Debug)]
 #[archive(check_bytes)]
@@ -61,33 +484,493 @@ where
 pub struct FanConfigRKYV {
     pub interval: u64,
     pub hysteresis: Hysteresis,
-    pub matrix: TempMatrixRKYV,
+    pub filter: FilterConfig,
+    pub sensors: Vec<SensorRKYV>,
+    pub failsafe_speed: u8,
+    pub critical: Option<CriticalTemperature>,
+    pub retry: RetryPolicy,
+    pub refresh_interval: Option<u64>,
+    pub slew_rate: SlewRate,
+    pub min_hold_time: u64,
+    pub spin_up: Option<SpinUp>,
+    pub min_running_speed: u8,
+    pub profiles: Vec<FanProfileRKYV>,
+    pub schedule: Vec<ScheduleEntry>,
 }
 impl Default for FanConfigRKYV {
     fn default() -> Self {
         FanConfigRKYV {
             interval: 10000u64,
-            hysteresis: Hysteresis {
-                amount: 4u8,
-                only_way_down: true,
-            },
-            matrix: matrix_mapper(vec![[55u8, 10u8], [60u8, 40u8], [65u8, 100u8]]),
+            hysteresis: Hysteresis::default(),
+            filter: FilterConfig::default(),
+            sensors: vec![SensorRKYV {
+                name: String::from(MAIN_SENSOR_NAME),
+                temperature_source: TemperatureSourceConfig::default(),
+                control: FanControl::Matrix(matrix_mapper(
+                    vec![[55u8, 10u8], [60u8, 40u8], [65u8, 100u8]],
+                    Interpolation::default(),
+                )),
+            }],
+            failsafe_speed: default_failsafe_speed(),
+            critical: None,
+            retry: RetryPolicy::default(),
+            refresh_interval: None,
+            slew_rate: SlewRate::default(),
+            min_hold_time: 0u64,
+            spin_up: None,
+            min_running_speed: 0u8,
+            profiles: Vec::new(),
+            schedule: Vec::new(),
+        }
+    }
+}
+
+pub const MAIN_SENSOR_NAME: &str = "main";
+// The kick blocks the fan loop, including the failsafe and critical checks.
+const MAX_SPIN_UP_DURATION: u64 = 5000;
+
+fn validate_pid_config(pid: &PidConfig) -> Result<(), String> {
+    if !pid.target.is_finite() {
+        return Err(format!(
+            "The PID target must be a number. Found: {}",
+            pid.target
+        ));
+    }
+
+    let valid_gain = |gain: f32| gain.is_finite() && gain >= 0.0;
+    if !(valid_gain(pid.kp) && valid_gain(pid.ki) && valid_gain(pid.kd)) {
+        return Err(String::from("The PID gains must be positive numbers"));
+    }
+
+    if pid.min_speed > pid.max_speed {
+        return Err(format!(
+            "The PID min_speed ({}) must not be greater than max_speed ({})",
+            pid.min_speed, pid.max_speed
+        ));
+    }
+
+    Ok(())
+}
+
+// The mean filter sleeps between samples for every sensor in turn, all of them must fit within the interval.
+fn validate_filter_config(
+    filter: &FilterConfig,
+    interval: u64,
+    sensor_count: usize,
+) -> Result<(), String> {
+    match filter {
+        FilterConfig::Ema { alpha } if !(*alpha > 0.0 && *alpha <= 1.0) => Err(format!(
+            "The filter alpha must be greater than 0 and at most 1. Found: {}",
+            alpha
+        )),
+        FilterConfig::Median { window: 0 } => {
+            Err(String::from("The filter window must be at least 1"))
+        }
+        FilterConfig::Mean { samples: 0, .. } => {
+            Err(String::from("The filter samples must be at least 1"))
+        }
+        FilterConfig::Mean {
+            samples,
+            sample_interval,
+        } => {
+            let sampling_time = (*samples as u64 - 1)
+                .checked_mul(*sample_interval)
+                .and_then(|sampling_time| sampling_time.checked_mul(sensor_count as u64));
+            match sampling_time {
+                Some(sampling_time) if sampling_time < interval => Ok(()),
+                _ => Err(format!(
+                    "The filter samples of all the sensors must be taken within the fan interval ({} ms)",
+                    interval
+                )),
+            }
+        }
+        _ => Ok(()),
+    }
+}
+
+fn map_fan_profiles(
+    profiles: Vec<FanProfileYAML>,
+    interpolation: Interpolation,
+    critical: Option<&CriticalTemperature>,
+) -> Result<Vec<FanProfileRKYV>, String> {
+    let mut mapped_profiles: Vec<FanProfileRKYV> = Vec::with_capacity(profiles.len());
+
+    for profile in profiles {
+        if mapped_profiles
+            .iter()
+            .any(|mapped_profile| mapped_profile.name == profile.name)
+        {
+            return Err(format!("Duplicated fan profile name: {}", profile.name));
+        }
+
+        // The speed cap is lifted above the critical temperature, so it can not be left without one.
+        let critical_temperature = profile
+            .critical_temperature
+            .or(critical.map(|critical| critical.temperature));
+        if profile.max_speed.is_some() && critical_temperature.is_none() {
+            return Err(format!(
+                "The fan profile {} has a max_speed but no critical_temperature",
+                profile.name
+            ));
+        }
+
+        mapped_profiles.push(FanProfileRKYV {
+            name: profile.name,
+            curve: matrix_mapper(
+                profile.matrix,
+                profile.interpolation.unwrap_or(interpolation),
+            ),
+            max_speed: profile.max_speed,
+            critical_temperature,
+        });
+    }
+
+    Ok(mapped_profiles)
+}
+
+fn map_schedule(
+    schedule: Vec<ScheduleEntryYAML>,
+    profiles: &[FanProfileRKYV],
+) -> Result<Vec<ScheduleEntry>, String> {
+    schedule
+        .into_iter()
+        .map(|entry| {
+            if !profiles.iter().any(|profile| profile.name == entry.profile) {
+                return Err(format!(
+                    "The schedule uses an unknown fan profile: {}",
+                    entry.profile
+                ));
+            }
+
+            // No days means every day.
+            let days = if entry.days.is_empty() {
+                0b111_1111
+            } else {
+                entry
+                    .days
+                    .iter()
+                    .fold(0u8, |days, &weekday| days | 1 << weekday as u8)
+            };
+
+            Ok(ScheduleEntry {
+                profile: entry.profile,
+                start: entry.time.0,
+                end: entry.time.1,
+                days,
+            })
+        })
+        .collect()
+}
+
+// The top level matrix (or PID controller) and temperature source become the first sensor.
+fn map_fan_config(fan_config: FanConfigYAML) -> Result<FanConfigRKYV, String> {
+    let mut sensors: Vec<SensorRKYV> = Vec::with_capacity(fan_config.sensors.len() + 1);
+
+    // The PID controller ignores the hysteresis, only the extra sensors use it.
+    let hysteresis = match (fan_config.hysteresis, fan_config.mode) {
+        (Some(hysteresis), _) => hysteresis,
+        (None, FanMode::Pid) => Hysteresis::default(),
+        (None, FanMode::Matrix) => {
+            return Err(String::from(
+                "The matrix mode requires a fan_config hysteresis section",
+            ));
+        }
+    };
+
+    let main_control = match (fan_config.mode, fan_config.pid, fan_config.matrix) {
+        (FanMode::Pid, Some(pid), _) => {
+            validate_pid_config(&pid)?;
+            Some(FanControl::Pid(pid))
+        }
+        (FanMode::Pid, None, _) => {
+            return Err(String::from(
+                "The pid mode requires a fan_config pid section",
+            ));
+        }
+        (FanMode::Matrix, _, Some(matrix)) => Some(FanControl::Matrix(matrix_mapper(
+            matrix,
+            fan_config.interpolation,
+        ))),
+        (FanMode::Matrix, _, None) => None,
+    };
+    if let Some(control) = main_control {
+        sensors.push(SensorRKYV {
+            name: String::from(MAIN_SENSOR_NAME),
+            temperature_source: fan_config.temperature_source,
+            control,
+        });
+    }
+
+    for sensor in fan_config.sensors {
+        if sensors
+            .iter()
+            .any(|mapped_sensor| mapped_sensor.name == sensor.name)
+        {
+            return Err(format!("Duplicated sensor name: {}", sensor.name));
         }
+        sensors.push(SensorRKYV {
+            name: sensor.name,
+            temperature_source: sensor.temperature_source,
+            control: FanControl::Matrix(matrix_mapper(
+                sensor.matrix,
+                sensor.interpolation.unwrap_or(fan_config.interpolation),
+            )),
+        });
+    }
+
+    validate_filter_config(&fan_config.filter, fan_config.interval, sensors.len())?;
+
+    if let (Some(0), _) | (_, Some(0)) = (fan_config.slew_rate.up, fan_config.slew_rate.down) {
+        return Err(String::from("The slew_rate steps must be at least 1"));
     }
+
+    if let Some(spin_up) = &fan_config.spin_up {
+        if spin_up.duration > MAX_SPIN_UP_DURATION {
+            return Err(format!(
+                "The spin_up duration must be at most {} ms. Found: {}",
+                MAX_SPIN_UP_DURATION, spin_up.duration
+            ));
+        }
+    }
+
+    if !(fan_config.retry.multiplier.is_finite() && fan_config.retry.multiplier >= 1.0) {
+        return Err(format!(
+            "The retry multiplier must be at least 1. Found: {}",
+            fan_config.retry.multiplier
+        ));
+    }
+
+    if sensors.is_empty() {
+        return Err(String::from(
+            "You must specify a fan_config matrix or at least one sensor",
+        ));
+    }
+
+    let profiles = map_fan_profiles(
+        fan_config.profiles,
+        fan_config.interpolation,
+        fan_config.critical.as_ref(),
+    )?;
+    let schedule = map_schedule(fan_config.schedule, &profiles)?;
+
+    // Profiles replace the curve of the main sensor.
+    let has_main_matrix = sensors.iter().any(|sensor| {
+        sensor.name == MAIN_SENSOR_NAME && matches!(sensor.control, FanControl::Matrix(_))
+    });
+    if !profiles.is_empty() && !has_main_matrix {
+        return Err(String::from(
+            "Fan profiles require a fan_config matrix in matrix mode",
+        ));
+    }
+
+    Ok(FanConfigRKYV {
+        interval: fan_config.interval,
+        hysteresis,
+        filter: fan_config.filter,
+        sensors,
+        failsafe_speed: fan_config.failsafe_speed,
+        critical: fan_config.critical,
+        retry: fan_config.retry,
+        refresh_interval: fan_config.refresh_interval,
+        slew_rate: fan_config.slew_rate,
+        min_hold_time: fan_config.min_hold_time,
+        spin_up: fan_config.spin_up,
+        min_running_speed: fan_config.min_running_speed,
+        profiles,
+        schedule,
+    })
 }
 
-#[derive(SerdeDeserialize, RkyvDeserialize, RkyvSerialize, Archive, Debug)]
+#[derive(SerdeDeserialize, RkyvDeserialize, RkyvSerialize, Archive, Debug, Clone)]
 #[archive(check_bytes)]
 #[archive_attr(derive(Debug))]
 pub struct PowerScript {
     pub location: String,
+    #[serde(default)]
     pub args: Vec<String>,
+    pub cwd: Option<String>,
+    #[serde(default)]
+    pub env: BTreeMap<String, String>,
+    // Milliseconds before the command is killed.
+    pub timeout: Option<u64>,
 }
+#[derive(SerdeDeserialize, RkyvDeserialize, RkyvSerialize, Archive, Debug, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+#[archive(check_bytes)]
+#[archive_attr(derive(Debug))]
+pub enum ButtonAction {
+    Reboot,
+    Shutdown,
+    ForcedShutdown,
+    RunScript,
+    NextFanProfile,
+    Ignore,
+}
+
+// A pulse from the case between `min` and `max` milliseconds, both included.
+#[derive(SerdeDeserialize, RkyvDeserialize, RkyvSerialize, Archive, Debug, Clone)]
+#[archive(check_bytes)]
+#[archive_attr(derive(Debug))]
+pub struct ButtonGesture {
+    pub min: u32,
+    pub max: u32,
+    pub action: ButtonAction,
+    pub script: Option<PowerScript>,
+    #[serde(default)]
+    pub continue_listening: bool,
+}
+
+// `presses` pulses in a row, each between `min` and `max` milliseconds and within the window of the previous one.
+#[derive(SerdeDeserialize, RkyvDeserialize, RkyvSerialize, Archive, Debug, Clone)]
+#[archive(check_bytes)]
+#[archive_attr(derive(Debug))]
+pub struct MultiPressGesture {
+    pub presses: u8,
+    pub min: u32,
+    pub max: u32,
+    pub action: ButtonAction,
+    pub script: Option<PowerScript>,
+    #[serde(default)]
+    pub continue_listening: bool,
+}
+impl MultiPressGesture {
+    fn matches(&self, pulses_ms: &[u32]) -> bool {
+        pulses_ms
+            .iter()
+            .all(|&pulse_ms| self.min <= pulse_ms && pulse_ms <= self.max)
+    }
+}
+
+#[derive(SerdeDeserialize, RkyvDeserialize, RkyvSerialize, Archive, Debug, Clone)]
+#[serde(default)]
+#[archive(check_bytes)]
+#[archive_attr(derive(Debug))]
+pub struct ButtonConfig {
+    pub gestures: Vec<ButtonGesture>,
+    pub multi_press_window: u64,
+    pub multi_press: Vec<MultiPressGesture>,
+}
+// The pulses sent by the Argon One v2 case firmware.
+impl Default for ButtonConfig {
+    fn default() -> Self {
+        let gesture = |min: u32, max: u32, action: ButtonAction| ButtonGesture {
+            min,
+            max,
+            action,
+            script: None,
+            continue_listening: false,
+        };
+        ButtonConfig {
+            gestures: vec![
+                gesture(10, 29, ButtonAction::Reboot),
+                gesture(30, 49, ButtonAction::Shutdown),
+                gesture(50, 79, ButtonAction::ForcedShutdown),
+            ],
+            multi_press_window: 1000,
+            multi_press: vec![],
+        }
+    }
+}
+impl ButtonConfig {
+    pub fn gesture(&self, pulse_ms: u32) -> Option<&ButtonGesture> {
+        self.gestures
+            .iter()
+            .find(|gesture| gesture.min <= pulse_ms && pulse_ms <= gesture.max)
+    }
+
+    pub fn multi_press_gesture(&self, pulses_ms: &[u32]) -> Option<&MultiPressGesture> {
+        self.multi_press.iter().find(|gesture| {
+            usize::from(gesture.presses) == pulses_ms.len() && gesture.matches(pulses_ms)
+        })
+    }
+
+    // Whether another pulse could still complete a multi-press gesture.
+    pub fn expects_more_presses(&self, pulses_ms: &[u32]) -> bool {
+        self.multi_press.iter().any(|gesture| {
+            usize::from(gesture.presses) > pulses_ms.len() && gesture.matches(pulses_ms)
+        })
+    }
+}
+
+fn validate_button_config(button: &ButtonConfig) -> Result<(), String> {
+    for (index, gesture) in button.gestures.iter().enumerate() {
+        if gesture.min > gesture.max {
+            return Err(format!(
+                "The button gesture min ({}) must not be greater than max ({})",
+                gesture.min, gesture.max
+            ));
+        }
+
+        if let (ButtonAction::RunScript, None) = (gesture.action, &gesture.script) {
+            return Err(format!(
+                "The button gesture {}-{}ms runs a script but has no script",
+                gesture.min, gesture.max
+            ));
+        }
+
+        let overlapping_gesture = button.gestures[..index]
+            .iter()
+            .find(|other| gesture.min <= other.max && other.min <= gesture.max);
+        if let Some(other) = overlapping_gesture {
+            return Err(format!(
+                "The button gestures {}-{}ms and {}-{}ms overlap",
+                other.min, other.max, gesture.min, gesture.max
+            ));
+        }
+    }
+
+    if button.multi_press_window == 0 {
+        return Err(String::from(
+            "The button multi_press_window must be greater than 0",
+        ));
+    }
+
+    for (index, gesture) in button.multi_press.iter().enumerate() {
+        if gesture.presses < 2 {
+            return Err(format!(
+                "A multi-press gesture requires at least 2 presses. Found: {}",
+                gesture.presses
+            ));
+        }
+
+        if gesture.min > gesture.max {
+            return Err(format!(
+                "The multi-press gesture min ({}) must not be greater than max ({})",
+                gesture.min, gesture.max
+            ));
+        }
+
+        if let (ButtonAction::RunScript, None) = (gesture.action, &gesture.script) {
+            return Err(format!(
+                "The {} presses gesture runs a script but has no script",
+                gesture.presses
+            ));
+        }
+
+        let overlapping_gesture = button.multi_press[..index].iter().find(|other| {
+            other.presses == gesture.presses && gesture.min <= other.max && other.min <= gesture.max
+        });
+        if let Some(other) = overlapping_gesture {
+            return Err(format!(
+                "The {} presses gestures {}-{}ms and {}-{}ms overlap",
+                gesture.presses, other.min, other.max, gesture.min, gesture.max
+            ));
+        }
+    }
+
+    Ok(())
+}
+
 #[derive(SerdeDeserialize, Debug)]
 pub struct ArgonConfigYAML {
     pub fan_config: FanConfigYAML,
     pub shutdown_script: Option<PowerScript>,
     pub reboot_script: Option<PowerScript>,
+    pub forced_shutdown_script: Option<PowerScript>,
+    #[serde(default)]
+    pub button: ButtonConfig,
+    #[serde(default)]
+    pub watch_config: bool,
 }
 
 #[derive(RkyvDeserialize, RkyvSerialize, Archive, Debug)]
@@ -97,6 +980,9 @@ pub struct ArgonConfigRKYV {
     pub fan_config: FanConfigRKYV,
     pub shutdown_script: Option<PowerScript>,
     pub reboot_script: Option<PowerScript>,
+    pub forced_shutdown_script: Option<PowerScript>,
+    pub button: ButtonConfig,
+    pub watch_config: bool,
 }
 impl Default for ArgonConfigRKYV {
     fn default() -> Self {
@@ -104,6 +990,9 @@ impl Default for ArgonConfigRKYV {
             fan_config: FanConfigRKYV::default(),
             shutdown_script: None,
             reboot_script: None,
+            forced_shutdown_script: None,
+            button: ButtonConfig::default(),
+            watch_config: false,
         }
     }
 }
@@ -113,6 +1002,25 @@ pub fn load_argon_config<F: FnOnce(ArgonConfigValue)>(on_config_ready: F) {
     match_argon_config(initial_config, on_config_ready, RKYV_CONFIG_PATH);
 }
 
+// Runs the service with a config that was already validated, e.g. after a reload.
+pub fn apply_argon_config<F: FnOnce(ArgonConfigValue)>(
+    argon_config: ArgonConfigRKYV,
+    on_config_ready: F,
+) {
+    let config = archive_argon_config(RKYV_CONFIG_PATH, argon_config);
+    match_argon_config(config, on_config_ready, RKYV_CONFIG_PATH);
+}
+
+// Reads and validates the YAML config without touching the cache file.
+pub fn read_yaml_config() -> Result<ArgonConfigRKYV, String> {
+    let yaml_buffer = read(YAML_CONFIG_PATH).map_err(|e| e.to_string())?;
+    parse_argon_config(&yaml_buffer)
+}
+
+pub fn yaml_config_modified() -> io::Result<SystemTime> {
+    metadata(YAML_CONFIG_PATH).and_then(|meta| meta.modified())
+}
+
 pub fn load_initial_config() -> ConfigTypes {
     deserialize_argon_config(
         RKYV_CONFIG_PATH,
@@ -120,7 +1028,7 @@ pub fn load_initial_config() -> ConfigTypes {
     )
     .unwrap_or_else(|| {
         println!("Fallback to default config...");
-        ConfigTypes::Serialized(ArgonConfigRKYV::default())
+        ConfigTypes::Serialized(Box::default())
     })
 }
 
@@ -148,7 +1056,7 @@ fn remove_cache_on_error<F: FnOnce(ArgonConfigValue)>(
                         "Error removing cache file, fallback to default config: {}",
                         e
                     );
-                    on_config_ready(ArgonConfigValue::NonArchived(ArgonConfigRKYV::default()));
+                    on_config_ready(ArgonConfigValue::NonArchived(Box::default()));
                 }
             }
         }
@@ -159,7 +1067,7 @@ fn remove_cache_on_error<F: FnOnce(ArgonConfigValue)>(
 #[archive(check_bytes)]
 pub enum ArgonConfigValue<'a> {
     Archived(&'a ArchivedArgonConfigRKYV),
-    NonArchived(ArgonConfigRKYV),
+    NonArchived(Box<ArgonConfigRKYV>),
 }
 fn match_argon_config<F: FnOnce(ArgonConfigValue)>(
     loaded_argon_config: ConfigTypes,
@@ -202,7 +1110,7 @@ pub enum RkyvBuffers {
     Aligned(AlignedVec),
 }
 pub enum ConfigTypes {
-    Serialized(ArgonConfigRKYV),
+    Serialized(Box<ArgonConfigRKYV>),
     NonSerialized(RkyvBuffers),
 }
 fn deserialize_argon_config(
@@ -215,37 +1123,10 @@ fn deserialize_argon_config(
             Some(ConfigTypes::NonSerialized(RkyvBuffers::Raw(rkyv_buffer)))
         }
         Err(ReadFileError::NoCacheFoundError(yaml_buffer)) => {
-            let argon_config_result = serde_yaml::from_slice::<ArgonConfigYAML>(&yaml_buffer);
-            match argon_config_result {
-                Ok(argon_config) => {
+            match parse_argon_config(&yaml_buffer) {
+                Ok(rkyv_config) => {
                     println!("Not valid cache file found, creating file...");
-
-                    let rkyv_config = ArgonConfigRKYV {
-                        shutdown_script: argon_config.shutdown_script,
-                        reboot_script: argon_config.reboot_script,
-                        fan_config: FanConfigRKYV {
-                            interval: argon_config.fan_config.interval,
-                            hysteresis: argon_config.fan_config.hysteresis,
-                            matrix: matrix_mapper(argon_config.fan_config.matrix),
-                        },
-                    };
-                    let combined_result = rkyv::to_bytes::<ArgonConfigRKYV, 5120>(&rkyv_config)
-                        .map_err(|e| e.to_string())
-                        .and_then(|archived_bytes| {
-                            write(rkyv_path, &archived_bytes)
-                                .map_err(|e| e.to_string())
-                                .map(|_| archived_bytes)
-                        });
-
-                    match combined_result {
-                        Ok(archived_bytes) => Some(ConfigTypes::NonSerialized(
-                            RkyvBuffers::Aligned(archived_bytes),
-                        )),
-                        Err(e) => {
-                            eprintln!("Error saving argon config cache: {}", e);
-                            Some(ConfigTypes::Serialized(rkyv_config))
-                        }
-                    }
+                    Some(archive_argon_config(rkyv_path, rkyv_config))
                 }
                 Err(e) => {
                     eprintln!("There is an error with your YAML config: {}", e);
@@ -260,6 +1141,40 @@ fn deserialize_argon_config(
     }
 }
 
+fn parse_argon_config(yaml_buffer: &[u8]) -> Result<ArgonConfigRKYV, String> {
+    let argon_config =
+        serde_yaml::from_slice::<ArgonConfigYAML>(yaml_buffer).map_err(|e| e.to_string())?;
+
+    validate_button_config(&argon_config.button)?;
+
+    Ok(ArgonConfigRKYV {
+        fan_config: map_fan_config(argon_config.fan_config)?,
+        shutdown_script: argon_config.shutdown_script,
+        reboot_script: argon_config.reboot_script,
+        forced_shutdown_script: argon_config.forced_shutdown_script,
+        button: argon_config.button,
+        watch_config: argon_config.watch_config,
+    })
+}
+
+fn archive_argon_config(rkyv_path: &str, rkyv_config: ArgonConfigRKYV) -> ConfigTypes {
+    let combined_result = rkyv::to_bytes::<ArgonConfigRKYV, 5120>(&rkyv_config)
+        .map_err(|e| e.to_string())
+        .and_then(|archived_bytes| {
+            write(rkyv_path, &archived_bytes)
+                .map_err(|e| e.to_string())
+                .map(|_| archived_bytes)
+        });
+
+    match combined_result {
+        Ok(archived_bytes) => ConfigTypes::NonSerialized(RkyvBuffers::Aligned(archived_bytes)),
+        Err(e) => {
+            eprintln!("Error saving argon config cache: {}", e);
+            ConfigTypes::Serialized(Box::new(rkyv_config))
+        }
+    }
+}
+
 enum ReadFileError {
     YamlIoError(io::Error),
     NoCacheFoundError(Vec<u8>),
@@ -281,3 +1196,338 @@ fn read_file(rkyv_path: &str, yaml_path: &str) -> Result<Vec<u8>, ReadFileError>
     let yaml_buffer = read(yaml_path).map_err(|e| ReadFileError::YamlIoError(e))?;
     Err(ReadFileError::NoCacheFoundError(yaml_buffer))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_fan_config(fan_config: &str) -> Result<FanConfigRKYV, String> {
+        parse_argon_config(format!("fan_config:\n{}", fan_config).as_bytes())
+            .map(|argon_config| argon_config.fan_config)
+    }
+
+    const PID_CONFIG: &str = "
+  interval: 10000
+  mode: pid
+  pid: { target: 55, kp: 4, ki: 0.1 }
+";
+
+    fn parse_hysteresis(hysteresis: &str) -> Result<Hysteresis, String> {
+        parse_fan_config(&format!("{}  hysteresis: {}\n", PID_CONFIG, hysteresis))
+            .map(|fan_config| fan_config.hysteresis)
+    }
+
+    #[test]
+    fn hysteresis_rise_and_fall() {
+        let hysteresis = parse_hysteresis("{ rise: 1.5, fall: 4 }").unwrap();
+
+        assert_eq!((hysteresis.rise, hysteresis.fall), (1.5, 4.0));
+        assert!(hysteresis.exceeded(2.0));
+        assert!(!hysteresis.exceeded(1.5));
+        assert!(hysteresis.exceeded(-4.5));
+        assert!(!hysteresis.exceeded(-4.0));
+    }
+
+    #[test]
+    fn legacy_hysteresis_is_mapped_to_rise_and_fall() {
+        let hysteresis = parse_hysteresis("{ amount: 3, only_way_down: true }").unwrap();
+        assert_eq!((hysteresis.rise, hysteresis.fall), (0.0, 3.0));
+
+        let hysteresis = parse_hysteresis("{ amount: 3, only_way_down: false }").unwrap();
+        assert_eq!((hysteresis.rise, hysteresis.fall), (3.0, 3.0));
+
+        let hysteresis = parse_hysteresis("{ amount: 5 }").unwrap();
+        assert_eq!((hysteresis.rise, hysteresis.fall), (5.0, 5.0));
+    }
+
+    #[test]
+    fn invalid_hysteresis_is_rejected() {
+        for hysteresis in [
+            "{ rise: .nan, fall: 4 }",
+            "{ rise: 0, fall: .nan }",
+            "{ rise: .inf, fall: 4 }",
+            "{ rise: -1, fall: 4 }",
+            "{ rise: 0, fall: 4, amount: 4 }",
+            "{ rise: 0 }",
+        ] {
+            assert!(
+                parse_hysteresis(hysteresis).is_err(),
+                "{} is valid",
+                hysteresis
+            );
+        }
+    }
+
+    const SUNDAY: u8 = 0;
+    const MONDAY: u8 = 1;
+    const FRIDAY: u8 = 5;
+    const SATURDAY: u8 = 6;
+
+    fn schedule_entry(start: u16, end: u16, days: &[u8]) -> ScheduleEntry {
+        ScheduleEntry {
+            profile: String::from("quiet"),
+            start,
+            end,
+            days: days.iter().fold(0, |days, &day| days | 1 << day),
+        }
+    }
+
+    #[test]
+    fn schedule_window_within_a_day() {
+        let entry = schedule_entry(9 * 60, 17 * 60, &[MONDAY]);
+
+        assert!(!entry.is_active(MONDAY, 9 * 60 - 1));
+        assert!(entry.is_active(MONDAY, 9 * 60));
+        assert!(entry.is_active(MONDAY, 17 * 60 - 1));
+        assert!(!entry.is_active(MONDAY, 17 * 60));
+        assert!(!entry.is_active(FRIDAY, 12 * 60));
+    }
+
+    #[test]
+    fn schedule_window_wraps_past_midnight() {
+        let entry = schedule_entry(22 * 60, 7 * 60, &[FRIDAY]);
+
+        assert!(!entry.is_active(FRIDAY, 22 * 60 - 1));
+        assert!(entry.is_active(FRIDAY, 22 * 60));
+        assert!(entry.is_active(FRIDAY, 23 * 60 + 59));
+        // After midnight the window belongs to the day it started.
+        assert!(entry.is_active(SATURDAY, 0));
+        assert!(entry.is_active(SATURDAY, 7 * 60 - 1));
+        assert!(!entry.is_active(SATURDAY, 7 * 60));
+        assert!(!entry.is_active(SATURDAY, 22 * 60));
+        assert!(!entry.is_active(FRIDAY, 3 * 60));
+    }
+
+    #[test]
+    fn schedule_window_wraps_from_saturday_to_sunday() {
+        let entry = schedule_entry(22 * 60, 7 * 60, &[SATURDAY]);
+
+        assert!(entry.is_active(SUNDAY, 6 * 60));
+        assert!(!entry.is_active(SUNDAY, 22 * 60));
+        assert!(!entry.is_active(MONDAY, 6 * 60));
+
+        let entry = schedule_entry(22 * 60, 7 * 60, &[SUNDAY]);
+        assert!(entry.is_active(MONDAY, 6 * 60));
+        assert!(!entry.is_active(SUNDAY, 6 * 60));
+    }
+
+    #[test]
+    fn empty_schedule_window_lasts_all_day() {
+        let entry = schedule_entry(8 * 60, 8 * 60, &[MONDAY]);
+
+        assert!(entry.is_active(MONDAY, 0));
+        assert!(entry.is_active(MONDAY, 23 * 60 + 59));
+        assert!(!entry.is_active(SUNDAY, 12 * 60));
+    }
+
+    #[test]
+    fn pid_mode_does_not_require_hysteresis() {
+        let fan_config = parse_fan_config(PID_CONFIG).unwrap();
+
+        assert_eq!(fan_config.hysteresis.rise, 0.0);
+        assert_eq!(fan_config.hysteresis.fall, 4.0);
+    }
+
+    #[test]
+    fn matrix_mode_requires_hysteresis() {
+        let error =
+            parse_fan_config("  interval: 10000\n  matrix: [[55, 10], [65, 100]]\n").unwrap_err();
+
+        assert!(error.contains("hysteresis"), "{}", error);
+    }
+
+    #[test]
+    fn filter_alpha_must_be_within_range() {
+        for alpha in [0.0, 1.5, f32::NAN, f32::NEG_INFINITY] {
+            let filter = FilterConfig::Ema { alpha };
+            assert!(
+                validate_filter_config(&filter, 10000, 1).is_err(),
+                "{}",
+                alpha
+            );
+        }
+        let filter = FilterConfig::Ema { alpha: 1.0 };
+        assert!(validate_filter_config(&filter, 10000, 1).is_ok());
+    }
+
+    #[test]
+    fn mean_filter_samples_of_every_sensor_must_fit_within_the_interval() {
+        let filter = FilterConfig::Mean {
+            samples: 5,
+            sample_interval: 1000,
+        };
+
+        assert!(validate_filter_config(&filter, 10000, 2).is_ok());
+        assert!(validate_filter_config(&filter, 10000, 3).is_err());
+    }
+
+    #[test]
+    fn mean_filter_overflow_is_rejected() {
+        let filter = FilterConfig::Mean {
+            samples: 3,
+            sample_interval: u64::MAX,
+        };
+
+        assert!(validate_filter_config(&filter, 10000, 1).is_err());
+    }
+
+    #[test]
+    fn spin_up_duration_is_capped() {
+        let fan_config = |duration: u64| {
+            format!(
+                "{}  spin_up: {{ speed: 100, duration: {} }}\n",
+                PID_CONFIG, duration
+            )
+        };
+
+        assert!(parse_fan_config(&fan_config(MAX_SPIN_UP_DURATION)).is_ok());
+        assert!(parse_fan_config(&fan_config(MAX_SPIN_UP_DURATION + 1)).is_err());
+    }
+
+    #[test]
+    fn retry_multiplier_must_be_at_least_one() {
+        let fan_config =
+            |multiplier: &str| format!("{}  retry: {{ multiplier: {} }}\n", PID_CONFIG, multiplier);
+
+        assert!(parse_fan_config(&fan_config("1")).is_ok());
+        assert!(parse_fan_config(&fan_config("2.5")).is_ok());
+        for multiplier in ["0.5", "-2", ".nan", ".inf"] {
+            assert!(
+                parse_fan_config(&fan_config(multiplier)).is_err(),
+                "{} is valid",
+                multiplier
+            );
+        }
+    }
+
+    #[test]
+    fn pid_values_must_be_finite() {
+        for pid in [
+            "{ target: .nan, kp: 4 }",
+            "{ target: 55, kp: .nan }",
+            "{ target: 55, kp: 4, ki: .inf }",
+            "{ target: 55, kp: 4, kd: .nan }",
+            "{ target: 55, kp: -1 }",
+        ] {
+            let fan_config = format!("  interval: 10000\n  mode: pid\n  pid: {}\n", pid);
+            assert!(parse_fan_config(&fan_config).is_err(), "{} is valid", pid);
+        }
+    }
+
+    fn parse_button_config(button: &str) -> Result<ButtonConfig, String> {
+        let button: ButtonConfig = serde_yaml::from_str(button).map_err(|e| e.to_string())?;
+        validate_button_config(&button)?;
+        Ok(button)
+    }
+
+    fn gesture_action(button: &ButtonConfig, pulse_ms: u32) -> Option<ButtonAction> {
+        button.gesture(pulse_ms).map(|gesture| gesture.action)
+    }
+
+    #[test]
+    fn default_gestures_match_the_legacy_pulse_widths() {
+        let button = ButtonConfig::default();
+        assert!(validate_button_config(&button).is_ok());
+
+        assert!(gesture_action(&button, 9).is_none());
+        for pulse_ms in [10, 20, 29] {
+            assert!(matches!(
+                gesture_action(&button, pulse_ms),
+                Some(ButtonAction::Reboot)
+            ));
+        }
+        for pulse_ms in [30, 40, 49] {
+            assert!(matches!(
+                gesture_action(&button, pulse_ms),
+                Some(ButtonAction::Shutdown)
+            ));
+        }
+        for pulse_ms in [50, 60, 79] {
+            assert!(matches!(
+                gesture_action(&button, pulse_ms),
+                Some(ButtonAction::ForcedShutdown)
+            ));
+        }
+        assert!(gesture_action(&button, 80).is_none());
+    }
+
+    #[test]
+    fn gesture_bounds_are_included() {
+        let button = parse_button_config(
+            "gestures:\n  - { min: 5, max: 5, action: ignore }\n  - { min: 6, max: 8, action: reboot }\n",
+        )
+        .unwrap();
+
+        assert!(gesture_action(&button, 4).is_none());
+        assert!(matches!(
+            gesture_action(&button, 5),
+            Some(ButtonAction::Ignore)
+        ));
+        assert!(matches!(
+            gesture_action(&button, 6),
+            Some(ButtonAction::Reboot)
+        ));
+        assert!(matches!(
+            gesture_action(&button, 8),
+            Some(ButtonAction::Reboot)
+        ));
+        assert!(gesture_action(&button, 9).is_none());
+    }
+
+    #[test]
+    fn invalid_gestures_are_rejected() {
+        for gestures in [
+            "[{ min: 20, max: 39, action: reboot }, { min: 39, max: 59, action: shutdown }]",
+            "[{ min: 40, max: 59, action: shutdown }, { min: 20, max: 79, action: reboot }]",
+            "[{ min: 40, max: 20, action: reboot }]",
+            "[{ min: 20, max: 39, action: run_script }]",
+        ] {
+            let button = format!("gestures: {}\n", gestures);
+            assert!(
+                parse_button_config(&button).is_err(),
+                "{} is valid",
+                gestures
+            );
+        }
+
+        let adjacent_gestures =
+            "gestures: [{ min: 20, max: 39, action: reboot }, { min: 40, max: 59, action: shutdown }]\n";
+        assert!(parse_button_config(adjacent_gestures).is_ok());
+    }
+
+    #[test]
+    fn multi_press_gestures() {
+        let button = parse_button_config(
+            "multi_press:\n  - { presses: 2, min: 20, max: 39, action: next_fan_profile }\n  - { presses: 3, min: 20, max: 39, action: ignore }\n",
+        )
+        .unwrap();
+
+        assert!(matches!(
+            button
+                .multi_press_gesture(&[20, 39])
+                .map(|gesture| gesture.action),
+            Some(ButtonAction::NextFanProfile)
+        ));
+        assert!(button.multi_press_gesture(&[20, 40]).is_none());
+        assert!(button.expects_more_presses(&[20, 39]));
+        assert!(!button.expects_more_presses(&[20, 39, 30]));
+        assert!(!button.expects_more_presses(&[50]));
+    }
+
+    #[test]
+    fn invalid_multi_press_gestures_are_rejected() {
+        for multi_press in [
+            "[{ presses: 1, min: 20, max: 39, action: reboot }]",
+            "[{ presses: 2, min: 39, max: 20, action: reboot }]",
+            "[{ presses: 2, min: 20, max: 39, action: run_script }]",
+            "[{ presses: 2, min: 20, max: 39, action: reboot }, { presses: 2, min: 30, max: 49, action: ignore }]",
+        ] {
+            let button = format!("multi_press: {}\n", multi_press);
+            assert!(parse_button_config(&button).is_err(), "{} is valid", multi_press);
+        }
+
+        let different_presses = "multi_press: [{ presses: 2, min: 20, max: 39, action: reboot }, { presses: 3, min: 20, max: 39, action: ignore }]\n";
+        assert!(parse_button_config(different_presses).is_ok());
+        assert!(parse_button_config("multi_press_window: 0\n").is_err());
+    }
+}
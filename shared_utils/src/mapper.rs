@@ -1,11 +1,9 @@
-use crate::load_yaml::{TempMatrixRKYV, TempMatrixYAML};
+use crate::load_yaml::{
+    ArchivedFanCurve, ArchivedInterpolation, FanCurve, Interpolation, TempMatrixYAML,
+};
 use std::cmp::Ordering;
-use std::collections::HashMap;
-
-pub fn matrix_mapper(mut matrix: TempMatrixYAML) -> TempMatrixRKYV {
-    let mut mapped_temperatures = HashMap::with_capacity(101);
-    let len = matrix.len();
 
+pub fn matrix_mapper(mut matrix: TempMatrixYAML, interpolation: Interpolation) -> FanCurve {
     matrix.sort_by(|a, b| {
         let cmp = a[0].cmp(&b[0]);
 
@@ -15,36 +13,244 @@ pub fn matrix_mapper(mut matrix: TempMatrixYAML) -> TempMatrixRKYV {
             cmp
         }
     });
+    // Repeated temperatures keep the highest speed.
+    matrix.dedup_by(|next_point, point| {
+        if next_point[0] == point[0] {
+            point[1] = next_point[1];
+            true
+        } else {
+            false
+        }
+    });
+
+    let tangents = match interpolation {
+        Interpolation::MonotoneCubic => monotone_tangents(&matrix),
+        Interpolation::Step | Interpolation::Linear => Vec::new(),
+    };
+
+    FanCurve {
+        interpolation,
+        points: matrix,
+        tangents,
+    }
+}
+
+impl FanCurve {
+    pub fn speed_at(&self, temperature: f32) -> u8 {
+        evaluate_curve(
+            &self.points,
+            &self.tangents,
+            self.interpolation,
+            temperature,
+        )
+    }
+}
+
+impl ArchivedFanCurve {
+    pub fn speed_at(&self, temperature: f32) -> u8 {
+        let interpolation = match self.interpolation {
+            ArchivedInterpolation::Step => Interpolation::Step,
+            ArchivedInterpolation::Linear => Interpolation::Linear,
+            ArchivedInterpolation::MonotoneCubic => Interpolation::MonotoneCubic,
+        };
+        evaluate_curve(&self.points, &self.tangents, interpolation, temperature)
+    }
+}
+
+// The fan is off below the first point and stays at the last point speed above it.
+fn evaluate_curve(
+    points: &[[u8; 2]],
+    tangents: &[f32],
+    interpolation: Interpolation,
+    temperature: f32,
+) -> u8 {
+    let (first_point, last_point) = match (points.first(), points.last()) {
+        (Some(first_point), Some(last_point)) => (first_point, last_point),
+        _ => return 0,
+    };
+
+    if temperature < first_point[0] as f32 {
+        return 0;
+    }
 
-    let matrix_iter = matrix.iter().zip(matrix.iter().skip(1)).enumerate();
-    for (index, (current_point, next_point)) in matrix_iter {
-        let initial_point = if index == 0 { 0 } else { current_point[0] };
-        let final_point = if index == len - 2 { 100 } else { next_point[0] };
-
-        for temperature in initial_point..=final_point {
-            let fan_speed = if initial_point == 0 && temperature < current_point[0] {
-                0
-            } else if final_point == 100 && temperature > next_point[0] {
-                next_point[1]
-            } else {
-                linear_interpolation(
+    for (index, segment) in points.windows(2).enumerate() {
+        let (current_point, next_point) = (segment[0], segment[1]);
+        if temperature < next_point[0] as f32 {
+            let fan_speed = match interpolation {
+                Interpolation::Step => current_point[1] as f32,
+                Interpolation::Linear => linear_interpolation(
                     current_point[0] as f32,
                     current_point[1] as f32,
                     next_point[0] as f32,
                     next_point[1] as f32,
-                    temperature as f32,
-                )
-                .round() as u8
+                    temperature,
+                ),
+                Interpolation::MonotoneCubic => hermite_interpolation(
+                    current_point[0] as f32,
+                    current_point[1] as f32,
+                    tangents[index],
+                    next_point[0] as f32,
+                    next_point[1] as f32,
+                    tangents[index + 1],
+                    temperature,
+                ),
             };
-
-            mapped_temperatures.insert(temperature, fan_speed);
+            return fan_speed.round().clamp(0.0, 100.0) as u8;
         }
     }
 
-    mapped_temperatures
+    last_point[1]
 }
 
 #[inline]
 fn linear_interpolation(x1: f32, y1: f32, x2: f32, y2: f32, x: f32) -> f32 {
     y1 + (x - x1) * ((y2 - y1) / (x2 - x1))
 }
+
+#[inline]
+fn hermite_interpolation(x1: f32, y1: f32, m1: f32, x2: f32, y2: f32, m2: f32, x: f32) -> f32 {
+    let h = x2 - x1;
+    let t = (x - x1) / h;
+    let t2 = t * t;
+    let t3 = t2 * t;
+
+    (2.0 * t3 - 3.0 * t2 + 1.0) * y1
+        + (t3 - 2.0 * t2 + t) * h * m1
+        + (-2.0 * t3 + 3.0 * t2) * y2
+        + (t3 - t2) * h * m2
+}
+
+// Fritsch-Carlson tangents, they keep the curve monotone between points so it never overshoots.
+fn monotone_tangents(points: &[[u8; 2]]) -> Vec<f32> {
+    let len = points.len();
+    if len < 2 {
+        return vec![0.0; len];
+    }
+
+    let secants: Vec<f32> = points
+        .windows(2)
+        .map(|segment| {
+            (segment[1][1] as f32 - segment[0][1] as f32)
+                / (segment[1][0] as f32 - segment[0][0] as f32)
+        })
+        .collect();
+
+    let mut tangents = Vec::with_capacity(len);
+    tangents.push(secants[0]);
+    for secant_pair in secants.windows(2) {
+        let (previous_secant, next_secant) = (secant_pair[0], secant_pair[1]);
+        if previous_secant * next_secant <= 0.0 {
+            tangents.push(0.0);
+        } else {
+            tangents.push((previous_secant + next_secant) / 2.0);
+        }
+    }
+    tangents.push(secants[len - 2]);
+
+    for (index, &secant) in secants.iter().enumerate() {
+        if secant == 0.0 {
+            tangents[index] = 0.0;
+            tangents[index + 1] = 0.0;
+            continue;
+        }
+
+        let alpha = tangents[index] / secant;
+        let beta = tangents[index + 1] / secant;
+        let magnitude = alpha * alpha + beta * beta;
+        if magnitude > 9.0 {
+            let tau = 3.0 / magnitude.sqrt();
+            tangents[index] = tau * alpha * secant;
+            tangents[index + 1] = tau * beta * secant;
+        }
+    }
+
+    tangents
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temperatures(from: u8, to: u8) -> impl Iterator<Item = f32> {
+        (from as u32 * 10..=to as u32 * 10).map(|tenths| tenths as f32 / 10.0)
+    }
+
+    #[test]
+    fn monotone_cubic_does_not_overshoot_between_points() {
+        let matrix = vec![[40, 0], [50, 10], [55, 90], [60, 90], [70, 100]];
+        let curve = matrix_mapper(matrix.clone(), Interpolation::MonotoneCubic);
+
+        for segment in matrix.windows(2) {
+            let (current_point, next_point) = (segment[0], segment[1]);
+            let mut last_speed = current_point[1];
+            for temperature in temperatures(current_point[0], next_point[0]) {
+                let fan_speed = curve.speed_at(temperature);
+                assert!(
+                    fan_speed >= current_point[1] && fan_speed <= next_point[1],
+                    "{} at {} is outside {:?}-{:?}",
+                    fan_speed,
+                    temperature,
+                    current_point,
+                    next_point
+                );
+                assert!(
+                    fan_speed >= last_speed,
+                    "The curve goes down at {}",
+                    temperature
+                );
+                last_speed = fan_speed;
+            }
+        }
+    }
+
+    #[test]
+    fn monotone_cubic_goes_through_the_points() {
+        let matrix = vec![[40, 10], [50, 30], [60, 80], [70, 100]];
+        let curve = matrix_mapper(matrix.clone(), Interpolation::MonotoneCubic);
+
+        for point in matrix {
+            assert_eq!(curve.speed_at(point[0] as f32), point[1]);
+        }
+    }
+
+    #[test]
+    fn step_holds_the_lower_point_speed() {
+        let curve = matrix_mapper(vec![[50, 20], [60, 50], [70, 100]], Interpolation::Step);
+
+        assert_eq!(curve.speed_at(50.0), 20);
+        assert_eq!(curve.speed_at(59.9), 20);
+        assert_eq!(curve.speed_at(60.0), 50);
+        assert_eq!(curve.speed_at(69.9), 50);
+        assert_eq!(curve.speed_at(70.0), 100);
+    }
+
+    #[test]
+    fn linear_interpolates_between_points() {
+        let curve = matrix_mapper(vec![[50, 20], [60, 50]], Interpolation::Linear);
+
+        assert_eq!(curve.speed_at(55.0), 35);
+    }
+
+    #[test]
+    fn speed_is_clamped_outside_the_matrix() {
+        for interpolation in [
+            Interpolation::Step,
+            Interpolation::Linear,
+            Interpolation::MonotoneCubic,
+        ] {
+            let curve = matrix_mapper(vec![[50, 20], [60, 50], [70, 90]], interpolation);
+
+            assert_eq!(curve.speed_at(49.9), 0);
+            assert_eq!(curve.speed_at(-10.0), 0);
+            assert_eq!(curve.speed_at(70.0), 90);
+            assert_eq!(curve.speed_at(120.0), 90);
+        }
+    }
+
+    #[test]
+    fn matrix_is_sorted_and_repeated_temperatures_keep_the_highest_speed() {
+        let curve = matrix_mapper(vec![[60, 50], [50, 20], [60, 40]], Interpolation::Linear);
+
+        assert_eq!(curve.points, vec![[50, 20], [60, 50]]);
+    }
+}
@@ -0,0 +1,110 @@
+use crate::load_yaml::{ArchivedPowerScript, PowerScript};
+use rkyv::option::ArchivedOption;
+use rkyv::{Deserialize, Infallible};
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::process::{Child, Command, ExitStatus};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+// How often a command with a timeout is checked.
+const TIMEOUT_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+pub enum PowerScriptConfigValue<'a> {
+    ArchivedPower(&'a ArchivedOption<ArchivedPowerScript>),
+    NonArchivedPower(Option<PowerScript>),
+}
+
+// A running command. A background thread waits for it, and kills it when the script timeout expires.
+pub struct PowerCommand {
+    watcher: JoinHandle<()>,
+}
+impl PowerCommand {
+    // Blocks until the command exits or is killed.
+    pub fn wait(self) {
+        let _ = self.watcher.join();
+    }
+}
+
+pub fn run_power_command(
+    script_config_option: &PowerScriptConfigValue,
+    fallback_command: &str,
+    fallback_args: Vec<&str>,
+) -> io::Result<PowerCommand> {
+    let power_script: Option<PowerScript> = match script_config_option {
+        PowerScriptConfigValue::ArchivedPower(archived_power_script) => (*archived_power_script)
+            .deserialize(&mut Infallible)
+            .unwrap(),
+        PowerScriptConfigValue::NonArchivedPower(non_archived_power_script) => {
+            non_archived_power_script.clone()
+        }
+    };
+
+    if let Some(power_script) = power_script {
+        let path = Path::new(&power_script.location);
+        match fs::metadata(path) {
+            Ok(metadata) => {
+                if metadata.is_file() {
+                    return run_script(&power_script);
+                }
+            }
+            Err(e) => {
+                eprintln!("No a valid script: {}", e);
+            }
+        }
+    }
+
+    let child = Command::new(fallback_command).args(fallback_args).spawn()?;
+    Ok(watch_command(String::from(fallback_command), child, None))
+}
+
+pub fn run_script(power_script: &PowerScript) -> io::Result<PowerCommand> {
+    let mut command = Command::new(&power_script.location);
+    command.args(&power_script.args).envs(&power_script.env);
+    if let Some(cwd) = &power_script.cwd {
+        command.current_dir(cwd);
+    }
+
+    let child = command.spawn()?;
+    Ok(watch_command(
+        power_script.location.clone(),
+        child,
+        power_script.timeout.map(Duration::from_millis),
+    ))
+}
+
+fn watch_command(name: String, mut child: Child, timeout: Option<Duration>) -> PowerCommand {
+    let watcher = thread::spawn(move || {
+        let exit_result = match timeout {
+            Some(timeout) => wait_with_timeout(&name, &mut child, timeout),
+            None => child.wait(),
+        };
+        match exit_result {
+            Ok(exit_status) if !exit_status.success() => {
+                eprintln!("{} finished with {}", name, exit_status);
+            }
+            Ok(_) => {}
+            Err(e) => {
+                eprintln!("Error waiting for {}: {}", name, e);
+            }
+        }
+    });
+
+    PowerCommand { watcher }
+}
+
+fn wait_with_timeout(name: &str, child: &mut Child, timeout: Duration) -> io::Result<ExitStatus> {
+    let started_at = Instant::now();
+    loop {
+        if let Some(exit_status) = child.try_wait()? {
+            return Ok(exit_status);
+        }
+        if started_at.elapsed() >= timeout {
+            eprintln!("{} did not finish within {:?}, killing it", name, timeout);
+            child.kill()?;
+            return child.wait();
+        }
+        thread::sleep(TIMEOUT_POLL_INTERVAL);
+    }
+}
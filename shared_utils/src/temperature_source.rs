@@ -0,0 +1,98 @@
+use crate::load_yaml::TemperatureSourceConfig;
+use std::fs::read_to_string;
+use std::io;
+use std::path::PathBuf;
+use systemstat::{Platform, System};
+
+pub trait TemperatureSource {
+    fn read_temperature(&mut self) -> io::Result<f32>;
+}
+
+pub struct SystemstatSource {
+    system: System,
+}
+impl SystemstatSource {
+    pub fn new() -> SystemstatSource {
+        SystemstatSource {
+            system: System::new(),
+        }
+    }
+}
+impl Default for SystemstatSource {
+    fn default() -> Self {
+        SystemstatSource::new()
+    }
+}
+impl TemperatureSource for SystemstatSource {
+    fn read_temperature(&mut self) -> io::Result<f32> {
+        self.system.cpu_temp()
+    }
+}
+
+// Thermal zones and hwmon inputs both report millidegrees Celsius.
+pub struct SysfsSource {
+    path: PathBuf,
+}
+impl SysfsSource {
+    pub fn new<P: Into<PathBuf>>(path: P) -> SysfsSource {
+        SysfsSource { path: path.into() }
+    }
+
+    pub fn thermal_zone(zone: u8) -> SysfsSource {
+        SysfsSource::new(format!("/sys/class/thermal/thermal_zone{}/temp", zone))
+    }
+}
+impl TemperatureSource for SysfsSource {
+    fn read_temperature(&mut self) -> io::Result<f32> {
+        let raw_value = read_to_string(&self.path)?;
+        raw_value
+            .trim()
+            .parse::<f32>()
+            .map(|millidegrees| millidegrees / 1000.0)
+            .map_err(|e| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("Invalid temperature in {}: {}", self.path.display(), e),
+                )
+            })
+    }
+}
+
+// Returns the values in order and keeps repeating the last one once exhausted.
+#[derive(Clone, Debug)]
+pub struct FixedSource {
+    values: Vec<f32>,
+    position: usize,
+}
+impl FixedSource {
+    pub fn new(values: Vec<f32>) -> FixedSource {
+        FixedSource {
+            values,
+            position: 0,
+        }
+    }
+}
+impl TemperatureSource for FixedSource {
+    fn read_temperature(&mut self) -> io::Result<f32> {
+        let value = self.values.get(self.position).or(self.values.last());
+        match value {
+            Some(&temperature) => {
+                self.position = (self.position + 1).min(self.values.len());
+                Ok(temperature)
+            }
+            None => Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                "No temperature values configured",
+            )),
+        }
+    }
+}
+
+pub fn open_temperature_source(config: &TemperatureSourceConfig) -> Box<dyn TemperatureSource> {
+    match config {
+        TemperatureSourceConfig::Systemstat => Box::new(SystemstatSource::new()),
+        TemperatureSourceConfig::ThermalZone { zone } => Box::new(SysfsSource::thermal_zone(*zone)),
+        TemperatureSourceConfig::Hwmon { path } => Box::new(SysfsSource::new(path)),
+        TemperatureSourceConfig::Fixed { values } => Box::new(FixedSource::new(values.clone())),
+    }
+}
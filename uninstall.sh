@@ -10,6 +10,8 @@ shutdown_button_service="$shutdown_button_binary_name.service"
 
 shutdown_binary_name="argon_shutdown"
 
+cli_binary_name="argonctl"
+
 
 # Stop running services
 if systemctl is-active --quiet $fan_service; then
@@ -45,6 +47,12 @@ if [ -f /usr/bin/$shutdown_button_binary_name ]; then
     echo "File /usr/bin/$shutdown_button_binary_name has been deleted."
 fi
 
+# Delete command line client executable
+if [ -f /usr/bin/$cli_binary_name ]; then
+    sudo rm -f /usr/bin/$cli_binary_name
+    echo "File /usr/bin/$cli_binary_name has been deleted."
+fi
+
 # Delete shutdown executable
 if [ -f /lib/systemd/system-shutdown/$shutdown_binary_name ]; then
     sudo rm -f /lib/systemd/system-shutdown/$shutdown_binary_name